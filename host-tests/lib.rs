@@ -0,0 +1,26 @@
+#![allow(dead_code)]
+
+#[path = "../src/backoff.rs"]
+mod backoff;
+#[path = "../src/buzzer_cmd.rs"]
+mod buzzer_cmd;
+#[path = "../src/command.rs"]
+mod command;
+#[path = "../src/config.rs"]
+mod config;
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/framing.rs"]
+mod framing;
+#[path = "../src/led_cmd.rs"]
+mod led_cmd;
+#[path = "../src/led_pattern.rs"]
+mod led_pattern;
+#[path = "../src/persistent.rs"]
+mod persistent;
+#[path = "../src/preset.rs"]
+mod preset;
+#[path = "../src/status.rs"]
+mod status;
+#[path = "../src/wifi.rs"]
+mod wifi;
@@ -0,0 +1,70 @@
+/* Connection attempts are retried with an exponential backoff, so that a buzzer starting before
+ * the server does not flood the access point. The delay is reset once connected.
+ */
+pub const CONNECT_BACKOFF_MIN_MS: u64 = 500;
+pub const CONNECT_BACKOFF_MAX_MS: u64 = 10_000;
+/* Retry delays are randomly spread by this ratio (in percent) in both directions, so that buzzers
+ * which lost the server at the same time do not all retry in lockstep
+ */
+const RETRY_JITTER_PERCENT: u64 = 25;
+
+/// Spread a retry delay around its nominal value, from a random value. The spread delay never
+/// leaves the backoff range.
+pub fn jittered_delay_ms(delay_ms: u64, random: u32) -> u64 {
+    let spread = delay_ms * RETRY_JITTER_PERCENT / 100;
+    let offset = u64::from(random) % (2 * spread + 1);
+    (delay_ms - spread + offset).clamp(CONNECT_BACKOFF_MIN_MS, CONNECT_BACKOFF_MAX_MS)
+}
+
+pub fn next_backoff(delay_ms: u64) -> u64 {
+    (delay_ms * 2).min(CONNECT_BACKOFF_MAX_MS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RANDOM_VALUES: [u32; 6] = [0, 1, 12_345, 0x8000_0000, u32::MAX - 1, u32::MAX];
+
+    #[test]
+    fn jitter_stays_within_the_backoff_range() {
+        let mut delay_ms = CONNECT_BACKOFF_MIN_MS;
+        loop {
+            for random in RANDOM_VALUES {
+                let jittered = jittered_delay_ms(delay_ms, random);
+                assert!(
+                    (CONNECT_BACKOFF_MIN_MS..=CONNECT_BACKOFF_MAX_MS).contains(&jittered),
+                    "{delay_ms}ms jittered to {jittered}ms"
+                );
+            }
+            if delay_ms == CONNECT_BACKOFF_MAX_MS {
+                break;
+            }
+            delay_ms = next_backoff(delay_ms);
+        }
+    }
+
+    #[test]
+    fn jitter_spreads_around_the_nominal_delay() {
+        let delay_ms = 2000;
+        let spread = delay_ms * RETRY_JITTER_PERCENT / 100;
+        for random in RANDOM_VALUES {
+            let jittered = jittered_delay_ms(delay_ms, random);
+            assert!((delay_ms - spread..=delay_ms + spread).contains(&jittered));
+        }
+        assert_eq!(jittered_delay_ms(delay_ms, 0), delay_ms - spread);
+        assert_eq!(
+            jittered_delay_ms(delay_ms, 2 * spread as u32),
+            delay_ms + spread
+        );
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(
+            next_backoff(CONNECT_BACKOFF_MIN_MS),
+            2 * CONNECT_BACKOFF_MIN_MS
+        );
+        assert_eq!(next_backoff(CONNECT_BACKOFF_MAX_MS), CONNECT_BACKOFF_MAX_MS);
+    }
+}
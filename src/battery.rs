@@ -0,0 +1,68 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use embassy_executor::Spawner;
+use embassy_time::Timer;
+use esp_hal::{
+    analog::adc::{Adc, AdcCalCurve, AdcConfig, AdcPin, Attenuation},
+    peripherals::{ADC1, GPIO0},
+};
+use log::{debug, info};
+
+/* The battery is sensed on GPIO0 through a 1:2 voltage divider, keeping a full LiPo cell (4.2V)
+ * within the ADC range
+ */
+const DIVIDER_RATIO: u32 = 2;
+/* The ADC is noisy, each reading is the average of several samples */
+const SAMPLE_COUNT: u32 = 8;
+const SAMPLE_INTERVAL_MS: u64 = 5;
+const READ_PERIOD_SECS: u64 = 10;
+/* LiPo cell voltages mapped to 0% and 100%, linearly in between */
+const EMPTY_MV: u16 = 3300;
+const FULL_MV: u16 = 4200;
+
+/* Last battery voltage in mV, 0 meaning not read yet */
+static MILLIVOLTS: AtomicU16 = AtomicU16::new(0);
+
+type SensePin = AdcPin<GPIO0<'static>, ADC1<'static>, AdcCalCurve<ADC1<'static>>>;
+
+pub struct Battery;
+
+impl Battery {
+    pub fn new(spawner: &Spawner, adc: ADC1<'static>, pin: GPIO0<'static>) -> Self {
+        let mut config = AdcConfig::new();
+        let pin = config.enable_pin_with_cal::<_, AdcCalCurve<ADC1>>(pin, Attenuation::_11dB);
+        let adc = Adc::new(adc, config).into_async();
+        spawner.spawn(battery_task(adc, pin).expect("Failed to start battery task"));
+        Battery
+    }
+
+    /// Battery charge in percent, once the battery has been read
+    pub fn percent(&self) -> Option<u8> {
+        match MILLIVOLTS.load(Ordering::Relaxed) {
+            0 => None,
+            mv => {
+                let charged = u32::from(mv.clamp(EMPTY_MV, FULL_MV) - EMPTY_MV);
+                Some((charged * 100 / u32::from(FULL_MV - EMPTY_MV)) as u8)
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn battery_task(mut adc: Adc<'static, ADC1<'static>, esp_hal::Async>, mut pin: SensePin) {
+    loop {
+        let mut total = 0;
+        for _ in 0..SAMPLE_COUNT {
+            total += u32::from(adc.read_oneshot(&mut pin).await);
+            Timer::after_millis(SAMPLE_INTERVAL_MS).await;
+        }
+        let mv = (total / SAMPLE_COUNT * DIVIDER_RATIO) as u16;
+        if MILLIVOLTS.load(Ordering::Relaxed) == 0 {
+            info!("Battery voltage: {mv}mV");
+        } else {
+            debug!("Battery voltage: {mv}mV");
+        }
+        MILLIVOLTS.store(mv.max(1), Ordering::Relaxed);
+        Timer::after_secs(READ_PERIOD_SECS).await;
+    }
+}
@@ -1,21 +1,220 @@
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Sender};
-use embassy_time::Timer;
-use esp_hal::gpio::{AnyPin, Input, InputConfig, Pull};
-use log::info;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
-const DEBOUNCE_MS: u64 = 100;
+use embassy_executor::Spawner;
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_sync::{
+    blocking_mutex::{
+        Mutex,
+        raw::{CriticalSectionRawMutex, NoopRawMutex},
+    },
+    channel::{Channel, Receiver, Sender},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{
+    gpio::{AnyPin, Input, InputConfig, Io, Level, Pin, Pull},
+    handler,
+    peripherals::{GPIO, IO_MUX},
+};
+use log::{debug, info};
+use static_cell::StaticCell;
+
+use crate::watchdog::{self, HEARTBEAT_SECS, Subsystem};
+
+/* Time for the contacts to stop bouncing, after which the pin level reflects the button state */
+const SETTLE_MS: u64 = 20;
+/* Minimum hold time for a press to also be reported as a long press */
+const LONG_PRESS_MS: u64 = 1500;
+/* Maximum time between two presses for the second one to make a double press */
+const DOUBLE_PRESS_WINDOW_MS: u64 = 400;
+
+/* While locked, presses are swallowed and no event is reported */
+static LOCKED: AtomicBool = AtomicBool::new(false);
+static LEVEL_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static LEVEL_RESPONSE: Signal<CriticalSectionRawMutex, Level> = Signal::new();
+/* Time of the last GPIO interrupt, taken by the button task once woken up by the falling edge.
+ * The task may only run a while after the interrupt fires, e.g. when the executor is busy
+ * erasing flash or handling a websocket frame.
+ */
+static EDGE_AT: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> = Mutex::new(Cell::new(None));
+/* Bit of the button pin in the GPIO interrupt status register */
+static BUTTON_PIN_MASK: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ButtonEvent {
+    /// Debounced button press, reported as soon as the button is pushed so that buzzes never
+    /// wait for the button release. `at` is the time of the falling edge, before debouncing.
+    Pressed { at: Instant },
+    /// Debounced button release, with the time the button was held
+    Released { held: Duration },
+    /// Reported after the `Released` event when the button was held long enough
+    LongPress { held: Duration },
+    /// Reported instead of `Pressed` for a press closely following a single press. A third press
+    /// starts a new sequence, and is reported as `Pressed` again.
+    DoublePress,
+}
+
+pub struct Button {
+    event_channel: Receiver<'static, NoopRawMutex, ButtonEvent, 1>,
+}
+
+static BUTTON_EVENT_CHANNEL: StaticCell<Channel<NoopRawMutex, ButtonEvent, 1>> = StaticCell::new();
+
+impl Button {
+    pub fn new(spawner: &Spawner, io_mux: IO_MUX<'static>, pin: AnyPin<'static>) -> Self {
+        BUTTON_PIN_MASK.store(1 << pin.number(), Ordering::Relaxed);
+        Io::new(io_mux).set_interrupt_handler(button_interrupt_handler);
+        let channel: &'static mut _ = BUTTON_EVENT_CHANNEL.init(Channel::new());
+        spawner.spawn(button_task(pin, channel.sender()).expect("Failed to start button task"));
+        Button {
+            event_channel: channel.receiver(),
+        }
+    }
+
+    pub async fn wait_for_event(&self) -> ButtonEvent {
+        self.event_channel.receive().await
+    }
+
+    /// Lock or unlock the button, e.g. to freeze buzzers between rounds
+    pub fn set_locked(&mut self, locked: bool) {
+        LOCKED.store(locked, Ordering::Relaxed);
+    }
+
+    pub fn is_locked(&self) -> bool {
+        LOCKED.load(Ordering::Relaxed)
+    }
+
+    /// Read the instantaneous raw level of the button pin, regardless of the debounce state
+    pub async fn read_level(&self) -> Level {
+        LEVEL_RESPONSE.reset();
+        LEVEL_REQUEST.signal(());
+        LEVEL_RESPONSE.wait().await
+    }
+}
+
+/// Stamp the button pin interrupts, and clear them as the HAL leaves that to user handlers.
+///
+/// With esp-hal 1.1, the GPIO interrupt handler reads the interrupt status before calling this
+/// handler, and only then wakes the async pin futures from the status it read: clearing the status
+/// here does not prevent `wait_for_falling_edge` from completing.
+#[handler]
+fn button_interrupt_handler() {
+    let mask = BUTTON_PIN_MASK.load(Ordering::Relaxed);
+    let gpio = GPIO::regs();
+    if gpio.status().read().bits() & mask == 0 {
+        return;
+    }
+    EDGE_AT.lock(|edge| edge.set(Some(Instant::now())));
+    // SAFETY: writing 1 to a bit of the write-1-to-clear register only clears the button status
+    gpio.status_w1tc().write(|w| unsafe { w.bits(mask) });
+}
 
 #[embassy_executor::task]
-pub async fn button_task(pin: AnyPin<'static>, sender: Sender<'static, NoopRawMutex, bool, 1>) {
+async fn button_task(pin: AnyPin<'static>, sender: Sender<'static, NoopRawMutex, ButtonEvent, 1>) {
     let config = InputConfig::default().with_pull(Pull::Up);
     let mut button = Input::new(pin, config);
+    /* Time of the last press reported as `Pressed`, which may start a double press */
+    let mut first_press: Option<Instant> = None;
+    /* Delay between the interrupt and the task handling the press, which grows when other tasks,
+     * such as the led task running a fast pattern, keep the executor busy
+     */
+    #[cfg(feature = "latency-stats")]
+    let mut wake_latency = crate::latency::LatencyStats::new("Button wake-up");
+    loop {
+        watchdog::feed(Subsystem::Button);
+        match select3(
+            button.wait_for_falling_edge(),
+            LEVEL_REQUEST.wait(),
+            Timer::after_secs(HEARTBEAT_SECS),
+        )
+        .await
+        {
+            Either3::First(_) => {
+                /* Stamp the press with the time the edge fired the interrupt, rather than the
+                 * time the task was woken up or the end of the debouncing
+                 */
+                let pressed_at = EDGE_AT.lock(Cell::take).unwrap_or_else(Instant::now);
+                #[cfg(feature = "latency-stats")]
+                wake_latency.record(pressed_at);
+                /* Only consider the press genuine if the pin is still low once bounces (or
+                 * glitches) have settled
+                 */
+                Timer::after_millis(SETTLE_MS).await;
+                if button.is_high() {
+                    debug!("Ignoring button glitch");
+                    continue;
+                }
+                if LOCKED.load(Ordering::Relaxed) {
+                    info!("Ignoring button push, button is locked");
+                    wait_for_release(&mut button).await;
+                    continue;
+                }
+                let event = match first_press.take() {
+                    Some(first)
+                        if pressed_at - first < Duration::from_millis(DOUBLE_PRESS_WINDOW_MS) =>
+                    {
+                        ButtonEvent::DoublePress
+                    }
+                    _ => {
+                        first_press = Some(pressed_at);
+                        ButtonEvent::Pressed { at: pressed_at }
+                    }
+                };
+                info!("Button pushed ({event:?})");
+                send_event(&sender, &button, event).await;
+                let held = wait_for_release(&mut button).await - pressed_at;
+                info!("Button released after {}ms", held.as_millis());
+                send_event(&sender, &button, ButtonEvent::Released { held }).await;
+                if held >= Duration::from_millis(LONG_PRESS_MS) {
+                    send_event(&sender, &button, ButtonEvent::LongPress { held }).await;
+                }
+            }
+            Either3::Second(_) => LEVEL_RESPONSE.signal(button.level()),
+            Either3::Third(_) => {}
+        }
+    }
+}
+
+/// Report an event, while still answering level requests. The main task may be waiting for the
+/// button level while the event channel is full, and would otherwise never get it.
+async fn send_event(
+    sender: &Sender<'static, NoopRawMutex, ButtonEvent, 1>,
+    button: &Input<'static>,
+    event: ButtonEvent,
+) {
+    loop {
+        match select(sender.send(event), LEVEL_REQUEST.wait()).await {
+            Either::First(()) => return,
+            Either::Second(()) => LEVEL_RESPONSE.signal(button.level()),
+        }
+    }
+}
+
+/// Wait for the button to be released, while still answering level requests, and return the time
+/// of the release. Like presses, a release is only considered genuine if the pin is still high
+/// once bounces have settled.
+async fn wait_for_release(button: &mut Input<'static>) -> Instant {
     loop {
-        button.wait_for_falling_edge().await;
-        info!("Button pushed !");
-        sender.send(true).await;
-        /* Quick and dirty deboucing, enough as long as we only need to
-         * detect single, short presses
-         */
-        Timer::after_millis(DEBOUNCE_MS).await;
+        /* A button held down is not a hung task */
+        watchdog::feed(Subsystem::Button);
+        match select3(
+            button.wait_for_high(),
+            LEVEL_REQUEST.wait(),
+            Timer::after_secs(HEARTBEAT_SECS),
+        )
+        .await
+        {
+            Either3::First(_) => {
+                let released_at = Instant::now();
+                Timer::after_millis(SETTLE_MS).await;
+                if button.is_high() {
+                    return released_at;
+                }
+                debug!("Ignoring button release bounce");
+            }
+            Either3::Second(_) => LEVEL_RESPONSE.signal(button.level()),
+            Either3::Third(_) => {}
+        }
     }
 }
@@ -0,0 +1,98 @@
+use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
+use embassy_sync::{
+    blocking_mutex::raw::NoopRawMutex,
+    channel::{Channel, Receiver, Sender},
+};
+use embassy_time::Timer;
+use esp_hal::{
+    gpio::AnyPin,
+    ledc::{
+        LSGlobalClkSource, Ledc, LowSpeed,
+        channel::{self, ChannelIFace},
+        timer::{self, TimerIFace},
+    },
+    peripherals::LEDC,
+    time::Rate,
+};
+use log::{debug, error};
+use static_cell::StaticCell;
+
+use crate::buzzer_cmd::BuzzerCmd;
+
+pub struct Buzzer {
+    cmd_channel: Sender<'static, NoopRawMutex, BuzzerCmd, 1>,
+}
+
+static BUZZER_CMD_CHANNEL: StaticCell<Channel<NoopRawMutex, BuzzerCmd, 1>> = StaticCell::new();
+
+impl Buzzer {
+    pub fn new(spawner: &Spawner, ledc: LEDC<'static>, pin: AnyPin<'static>) -> Self {
+        let channel: &'static mut _ = BUZZER_CMD_CHANNEL.init(Channel::new());
+        spawner.spawn(
+            buzzer_task(ledc, pin, channel.receiver()).expect("Failed to start buzzer task"),
+        );
+        Buzzer {
+            cmd_channel: channel.sender(),
+        }
+    }
+
+    /// Play a command without waiting: when the buzzer task is busy taking the previous command
+    /// into account, the new one is dropped
+    pub fn play(&mut self, cmd: BuzzerCmd) {
+        if self.cmd_channel.try_send(cmd).is_err() {
+            debug!("Dropping buzzer command {cmd:?}");
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn buzzer_task(
+    ledc: LEDC<'static>,
+    mut pin: AnyPin<'static>,
+    cmd_channel: Receiver<'static, NoopRawMutex, BuzzerCmd, 1>,
+) {
+    let mut ledc = Ledc::new(ledc);
+    ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+    let mut next = None;
+    loop {
+        let cmd = match next.take() {
+            Some(cmd) => cmd,
+            None => cmd_channel.receive().await,
+        };
+        let BuzzerCmd::Tone {
+            frequency_hz,
+            duration,
+        } = cmd
+        else {
+            continue;
+        };
+        /* The PWM timer frequency can only be changed by configuring it again, along with the
+         * channel using it
+         */
+        let mut pwm_timer = ledc.timer::<LowSpeed>(timer::Number::Timer0);
+        if let Err(e) = pwm_timer.configure(timer::config::Config {
+            duty: timer::config::Duty::Duty10Bit,
+            clock_source: timer::LSClockSource::APBClk,
+            frequency: Rate::from_hz(frequency_hz),
+        }) {
+            error!("Failed to configure buzzer timer: {e:?}");
+            continue;
+        }
+        let mut pwm_channel = ledc.channel(channel::Number::Channel0, pin.reborrow());
+        if let Err(e) = pwm_channel.configure(channel::config::Config {
+            timer: &pwm_timer,
+            duty_pct: 50,
+            drive_mode: esp_hal::gpio::DriveMode::PushPull,
+        }) {
+            error!("Failed to configure buzzer channel: {e:?}");
+            continue;
+        }
+        if let Either::Second(cmd) = select(Timer::after(duration), cmd_channel.receive()).await {
+            next = Some(cmd);
+        }
+        if let Err(e) = pwm_channel.set_duty(0) {
+            error!("Failed to silence buzzer: {e:?}");
+        }
+    }
+}
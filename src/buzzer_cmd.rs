@@ -0,0 +1,38 @@
+use embassy_time::Duration;
+
+use crate::error::CommandError;
+
+/* Audible range of a typical piezo element */
+const MIN_FREQUENCY_HZ: u32 = 100;
+const MAX_FREQUENCY_HZ: u32 = 10_000;
+const MAX_TONE_DURATION_MS: u32 = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuzzerCmd {
+    /// Stop the current tone, if any
+    Off,
+    /// Play a square wave tone. A new command interrupts the tone.
+    Tone {
+        frequency_hz: u32,
+        duration: Duration,
+    },
+}
+
+impl BuzzerCmd {
+    /// Build a tone requested by the host, rejecting inaudible or overly long ones. A null
+    /// duration stops the current tone.
+    pub fn tone(frequency_hz: u32, duration_ms: u32) -> Result<Self, CommandError> {
+        if duration_ms == 0 {
+            return Ok(BuzzerCmd::Off);
+        }
+        if !(MIN_FREQUENCY_HZ..=MAX_FREQUENCY_HZ).contains(&frequency_hz)
+            || duration_ms > MAX_TONE_DURATION_MS
+        {
+            return Err(CommandError::InvalidTone);
+        }
+        Ok(BuzzerCmd::Tone {
+            frequency_hz,
+            duration: Duration::from_millis(duration_ms.into()),
+        })
+    }
+}
@@ -0,0 +1,267 @@
+use serde::Deserialize;
+use serde_json_core as sj;
+
+use crate::buzzer_cmd::BuzzerCmd;
+use crate::config::{Config, ConfigUpdate, LogCategoriesUpdate};
+use crate::error::CommandError;
+use crate::led_cmd::{
+    LED_COUNT, LedCmd, MAX_HUE_SHIFT_DEG_PER_SEC, MAX_SEQUENCE_COLORS, MessageLedPattern,
+    MessageRawFrame, parse_bounded,
+};
+use crate::preset::PresetSlot;
+use crate::wifi::WifiNetworks;
+
+#[derive(Deserialize, Debug)]
+struct MessageType<'a> {
+    r#type: Option<&'a str>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageLogConfig {
+    targets: LogCategoriesUpdate,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageHueShift {
+    degrees_per_sec: f32,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageImportState {
+    state: Config,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageAck {
+    seq: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageTone {
+    frequency_hz: u32,
+    duration_ms: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageSleep {
+    enabled: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageLock {
+    locked: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageWifiCredentials {
+    networks: WifiNetworks,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessagePresetSlot<'a> {
+    slot: &'a str,
+}
+
+/* Commands only transit through the websocket event channel, whose few slots are statically
+ * allocated: boxing the credentials would only trade them for heap allocations
+ */
+#[allow(clippy::large_enum_variant)]
+pub enum Command {
+    Led(LedCmd),
+    Status,
+    SetPreset(PresetSlot, LedCmd),
+    TriggerPreset(PresetSlot),
+    Config(ConfigUpdate),
+    ExportState,
+    ImportState(Config),
+    ButtonLevel,
+    LogConfig(LogCategoriesUpdate),
+    SimulateBuzz,
+    WifiReconnect,
+    /// Access points to persist and connect to, in order of preference
+    WifiCredentials(WifiNetworks),
+    HueShift(f32),
+    Lock(bool),
+    Temperature,
+    Tone(BuzzerCmd),
+    /// Allow or prevent deep sleep on inactivity, e.g. during an active game
+    Sleep(bool),
+    /// Acknowledgement of the buzz with the given sequence number
+    Ack(u32),
+    /// Send the identification message again
+    Identify,
+    Reboot,
+    #[cfg(feature = "debug-commands")]
+    DumpTable,
+}
+
+/* Binary commands start with an opcode byte, followed by the command payload */
+#[cfg(feature = "binary-commands")]
+const OPCODE_LED: u8 = 0x01;
+#[cfg(feature = "binary-commands")]
+const OPCODE_STATUS: u8 = 0x02;
+#[cfg(feature = "binary-commands")]
+const OPCODE_HUE_SHIFT: u8 = 0x03;
+#[cfg(feature = "binary-commands")]
+const OPCODE_SIMULATE_BUZZ: u8 = 0x04;
+
+fn parse_slot(msg: &[u8]) -> Result<PresetSlot, CommandError> {
+    let (slot, _) =
+        sj::from_slice::<MessagePresetSlot>(msg).map_err(|_| CommandError::InvalidJson)?;
+    slot.slot.try_into()
+}
+
+/// Check a hue rotation rate, rejecting non-finite and too fast rates
+fn hue_shift(degrees_per_sec: f32) -> Result<Command, CommandError> {
+    if !(-MAX_HUE_SHIFT_DEG_PER_SEC..=MAX_HUE_SHIFT_DEG_PER_SEC).contains(&degrees_per_sec) {
+        return Err(CommandError::InvalidHueShift);
+    }
+    Ok(Command::HueShift(degrees_per_sec))
+}
+
+fn parse_led_pattern(msg: &[u8]) -> Result<LedCmd, CommandError> {
+    let pattern = parse_bounded::<MessageLedPattern>(msg, MAX_SEQUENCE_COLORS)?;
+    Ok(pattern.try_into()?)
+}
+
+impl Command {
+    /// Decode a command from a JSON websocket message. Messages without a top-level `type` field
+    /// are considered as LED pattern messages, like the ones of the `led` type.
+    pub fn parse(msg: &[u8]) -> Result<Self, CommandError> {
+        let (header, _) =
+            sj::from_slice::<MessageType>(msg).map_err(|_| CommandError::InvalidJson)?;
+        match header.r#type {
+            None | Some("led") => Ok(Command::Led(parse_led_pattern(msg)?)),
+            Some("status" | "status_request") => Ok(Command::Status),
+            Some("identify") => Ok(Command::Identify),
+            Some("reboot") => Ok(Command::Reboot),
+            Some("set_preset") => Ok(Command::SetPreset(
+                parse_slot(msg)?,
+                parse_led_pattern(msg)?,
+            )),
+            Some("trigger") => Ok(Command::TriggerPreset(parse_slot(msg)?)),
+            Some("button_level") => Ok(Command::ButtonLevel),
+            Some("temp") => Ok(Command::Temperature),
+            Some("tone") => {
+                let (tone, _) =
+                    sj::from_slice::<MessageTone>(msg).map_err(|_| CommandError::InvalidJson)?;
+                Ok(Command::Tone(BuzzerCmd::tone(
+                    tone.frequency_hz,
+                    tone.duration_ms,
+                )?))
+            }
+            Some("simulate_buzz") => Ok(Command::SimulateBuzz),
+            Some("raw") => {
+                let frame = parse_bounded::<MessageRawFrame>(msg, LED_COUNT)?;
+                Ok(Command::Led(frame.into()))
+            }
+            Some("wifi_reconnect") => Ok(Command::WifiReconnect),
+            Some("wifi_credentials") => {
+                let (credentials, _) = sj::from_slice::<MessageWifiCredentials>(msg)
+                    .map_err(|_| CommandError::InvalidJson)?;
+                Ok(Command::WifiCredentials(credentials.networks))
+            }
+            Some("rssi_mode") => Ok(Command::Led(LedCmd::RssiBreathe)),
+            #[cfg(feature = "debug-commands")]
+            Some("dump_table") => Ok(Command::DumpTable),
+            Some("hue_shift") => {
+                let (shift, _) = sj::from_slice::<MessageHueShift>(msg)
+                    .map_err(|_| CommandError::InvalidJson)?;
+                hue_shift(shift.degrees_per_sec)
+            }
+            Some("lock") => {
+                let (lock, _) =
+                    sj::from_slice::<MessageLock>(msg).map_err(|_| CommandError::InvalidJson)?;
+                Ok(Command::Lock(lock.locked))
+            }
+            Some("sleep") => {
+                let (sleep, _) =
+                    sj::from_slice::<MessageSleep>(msg).map_err(|_| CommandError::InvalidJson)?;
+                Ok(Command::Sleep(sleep.enabled))
+            }
+            Some("ack") => {
+                let (ack, _) =
+                    sj::from_slice::<MessageAck>(msg).map_err(|_| CommandError::InvalidJson)?;
+                Ok(Command::Ack(ack.seq))
+            }
+            Some("export_state") => Ok(Command::ExportState),
+            Some("import_state") => {
+                let (import, _) = sj::from_slice::<MessageImportState>(msg)
+                    .map_err(|_| CommandError::InvalidJson)?;
+                Ok(Command::ImportState(import.state))
+            }
+            Some("logcfg") => {
+                let (logcfg, _) = sj::from_slice::<MessageLogConfig>(msg)
+                    .map_err(|_| CommandError::InvalidJson)?;
+                Ok(Command::LogConfig(logcfg.targets))
+            }
+            Some("config") => {
+                let (update, _) =
+                    sj::from_slice::<ConfigUpdate>(msg).map_err(|_| CommandError::InvalidJson)?;
+                Ok(Command::Config(update))
+            }
+            Some(_) => Err(CommandError::UnknownType),
+        }
+    }
+
+    /// Decode a command from a binary websocket message, a compact alternative to JSON for high
+    /// frequency commands
+    #[cfg(feature = "binary-commands")]
+    pub fn parse_binary(msg: &[u8]) -> Result<Self, CommandError> {
+        let (&opcode, payload) = msg.split_first().ok_or(CommandError::InvalidBinary)?;
+        match opcode {
+            OPCODE_LED => Ok(Command::Led(LedCmd::from_binary(payload)?)),
+            OPCODE_STATUS => Ok(Command::Status),
+            OPCODE_HUE_SHIFT => {
+                let rate = payload
+                    .first_chunk::<4>()
+                    .ok_or(CommandError::InvalidBinary)?;
+                hue_shift(f32::from_le_bytes(*rate))
+            }
+            OPCODE_SIMULATE_BUZZ => Ok(Command::SimulateBuzz),
+            _ => Err(CommandError::UnknownType),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_hue_shift(degrees_per_sec: &str) -> Result<Command, CommandError> {
+        let msg = format!(r#"{{"type":"hue_shift","degrees_per_sec":{degrees_per_sec}}}"#);
+        Command::parse(msg.as_bytes())
+    }
+
+    #[test]
+    fn hue_shift_rates_are_bounded() {
+        for rate in ["-360", "0", "12.5", "360"] {
+            assert!(
+                matches!(parse_hue_shift(rate), Ok(Command::HueShift(_))),
+                "{rate} deg/s"
+            );
+        }
+        for rate in ["-361", "361"] {
+            assert!(
+                matches!(parse_hue_shift(rate), Err(CommandError::InvalidHueShift)),
+                "{rate} deg/s"
+            );
+        }
+    }
+
+    #[cfg(feature = "binary-commands")]
+    #[test]
+    fn non_finite_hue_shift_rates_are_rejected() {
+        for rate in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let mut msg = [OPCODE_HUE_SHIFT, 0, 0, 0, 0];
+            msg[1..].copy_from_slice(&rate.to_le_bytes());
+            assert!(
+                matches!(
+                    Command::parse_binary(&msg),
+                    Err(CommandError::InvalidHueShift)
+                ),
+                "{rate} deg/s"
+            );
+        }
+    }
+}
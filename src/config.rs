@@ -0,0 +1,302 @@
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ConfigError;
+use crate::led_cmd::LedCmd;
+use crate::wifi::WifiSettings;
+
+const MAX_WIFI_CHANNEL: u8 = 13;
+
+/// What to do with a buzz happening while the buzzer is not connected to the NBC
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OfflineBuzzPolicy {
+    /// Drop the buzz, and flash the LED to tell the player
+    #[default]
+    Drop,
+    /// Queue the buzz, to be sent once reconnected
+    Buffer,
+}
+
+/// Categories of LED feedback played by the buzzer on its own, each with its own brightness scale
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LedEvent {
+    Idle,
+    Buzz,
+    Alert,
+}
+
+pub const NAME_MAX_LEN: usize = 32;
+
+/// Log categories which can be toggled at runtime, each one matching the logs emitted by a group
+/// of modules
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct LogCategories {
+    pub network: bool,
+    pub ws: bool,
+    pub led: bool,
+    pub button: bool,
+}
+
+impl Default for LogCategories {
+    fn default() -> Self {
+        LogCategories {
+            network: true,
+            ws: true,
+            led: true,
+            button: true,
+        }
+    }
+}
+
+/// Partial update of the log categories received from the host
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct LogCategoriesUpdate {
+    network: Option<bool>,
+    ws: Option<bool>,
+    led: Option<bool>,
+    button: Option<bool>,
+}
+
+impl LogCategories {
+    pub fn apply(&self, update: &LogCategoriesUpdate) -> LogCategories {
+        LogCategories {
+            network: update.network.unwrap_or(self.network),
+            ws: update.ws.unwrap_or(self.ws),
+            led: update.led.unwrap_or(self.led),
+            button: update.button.unwrap_or(self.button),
+        }
+    }
+}
+
+/// Device configuration, persisted in flash and editable at runtime by the host
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// Human readable name, reported to the host in identification messages
+    pub name: String<NAME_MAX_LEN>,
+    /// Global LED brightness scale, 255 being full brightness
+    pub brightness: u8,
+    /// When disabled, the LED stays off whatever the received commands
+    pub led_enabled: bool,
+    /// Brightness scales of the idle, buzz feedback and alert patterns, applied on top of the
+    /// global brightness
+    pub idle_brightness: u8,
+    pub buzz_brightness: u8,
+    pub alert_brightness: u8,
+    /// Token used by the host to break ties between simultaneous buzzes. When unset, a token
+    /// derived from the device MAC address is used.
+    pub tiebreak: Option<u32>,
+    /// Log categories enabled at runtime
+    pub log: LogCategories,
+    /// Allow the host to simulate button pushes, for testing purposes only
+    pub allow_simulated_buzz: bool,
+    /// Fixed wifi channel of the access point, all channels being scanned when unset
+    pub wifi_channel: Option<u8>,
+    /// Fixed BSSID of the access point
+    pub wifi_bssid: Option<[u8; 6]>,
+    pub offline_buzz: OfflineBuzzPolicy,
+    /// Expect the host to acknowledge each buzz, retransmitting unacknowledged ones
+    pub buzz_ack: bool,
+    /// Beep on the piezo element when the button is pushed
+    pub sound_enabled: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            name: String::new(),
+            brightness: u8::MAX,
+            led_enabled: true,
+            idle_brightness: u8::MAX,
+            buzz_brightness: u8::MAX,
+            alert_brightness: u8::MAX,
+            tiebreak: None,
+            log: LogCategories::default(),
+            allow_simulated_buzz: false,
+            wifi_channel: None,
+            wifi_bssid: None,
+            offline_buzz: OfflineBuzzPolicy::default(),
+            buzz_ack: false,
+            sound_enabled: true,
+        }
+    }
+}
+
+/// Bulk configuration update received from the host: missing fields are left unchanged
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ConfigUpdate {
+    name: Option<String<NAME_MAX_LEN>>,
+    brightness: Option<u8>,
+    led_enabled: Option<bool>,
+    idle_brightness: Option<u8>,
+    buzz_brightness: Option<u8>,
+    alert_brightness: Option<u8>,
+    tiebreak: Option<u32>,
+    allow_simulated_buzz: Option<bool>,
+    /// Channel 0 clears the fixed channel
+    wifi_channel: Option<u8>,
+    /// An all-zero BSSID clears the fixed BSSID
+    wifi_bssid: Option<[u8; 6]>,
+    offline_buzz: Option<OfflineBuzzPolicy>,
+    buzz_ack: Option<bool>,
+    sound_enabled: Option<bool>,
+}
+
+impl Config {
+    /// Validate all fields of an update and build the resulting configuration. Nothing is applied
+    /// if any field is invalid.
+    pub fn apply(&self, update: &ConfigUpdate) -> Result<Config, ConfigError> {
+        let mut config = self.clone();
+        if let Some(name) = &update.name {
+            if name.trim().is_empty() || name.chars().any(char::is_control) {
+                return Err(ConfigError::InvalidName);
+            }
+            config.name = name.clone();
+        }
+        if let Some(brightness) = update.brightness {
+            config.brightness = brightness;
+        }
+        if let Some(led_enabled) = update.led_enabled {
+            config.led_enabled = led_enabled;
+        }
+        if let Some(idle_brightness) = update.idle_brightness {
+            config.idle_brightness = idle_brightness;
+        }
+        if let Some(buzz_brightness) = update.buzz_brightness {
+            config.buzz_brightness = buzz_brightness;
+        }
+        if let Some(alert_brightness) = update.alert_brightness {
+            config.alert_brightness = alert_brightness;
+        }
+        if let Some(tiebreak) = update.tiebreak {
+            config.tiebreak = Some(tiebreak);
+        }
+        if let Some(allow_simulated_buzz) = update.allow_simulated_buzz {
+            config.allow_simulated_buzz = allow_simulated_buzz;
+        }
+        match update.wifi_channel {
+            Some(0) => config.wifi_channel = None,
+            Some(channel) if channel <= MAX_WIFI_CHANNEL => config.wifi_channel = Some(channel),
+            Some(_) => return Err(ConfigError::InvalidWifiChannel),
+            None => {}
+        }
+        if let Some(bssid) = update.wifi_bssid {
+            config.wifi_bssid = (bssid != [0; 6]).then_some(bssid);
+        }
+        if let Some(offline_buzz) = update.offline_buzz {
+            config.offline_buzz = offline_buzz;
+        }
+        if let Some(buzz_ack) = update.buzz_ack {
+            config.buzz_ack = buzz_ack;
+        }
+        if let Some(sound_enabled) = update.sound_enabled {
+            config.sound_enabled = sound_enabled;
+        }
+        Ok(config)
+    }
+
+    /// Configuration shared when cloning settings from one buzzer to others, without the settings
+    /// specific to this buzzer: its tiebreak token, and the access point it is pinned to, which
+    /// depends on where it sits. LED presets, including the idle one, are not part of it either,
+    /// since they are only kept in RAM and loaded by the host after each connection.
+    pub fn export(&self) -> Config {
+        Config {
+            tiebreak: None,
+            wifi_channel: None,
+            wifi_bssid: None,
+            ..self.clone()
+        }
+    }
+
+    /// Validate a configuration exported from another buzzer and build the resulting
+    /// configuration, keeping the settings specific to this buzzer. The configuration is applied
+    /// as an update, so that it is validated the same way.
+    pub fn import(&self, state: Config) -> Result<Config, ConfigError> {
+        let update = ConfigUpdate {
+            name: Some(state.name),
+            brightness: Some(state.brightness),
+            led_enabled: Some(state.led_enabled),
+            idle_brightness: Some(state.idle_brightness),
+            buzz_brightness: Some(state.buzz_brightness),
+            alert_brightness: Some(state.alert_brightness),
+            tiebreak: None,
+            allow_simulated_buzz: Some(state.allow_simulated_buzz),
+            wifi_channel: None,
+            wifi_bssid: None,
+            offline_buzz: Some(state.offline_buzz),
+            buzz_ack: Some(state.buzz_ack),
+            sound_enabled: Some(state.sound_enabled),
+        };
+        Ok(Config {
+            log: state.log,
+            ..self.apply(&update)?
+        })
+    }
+
+    /// Scale a pattern played for the given event category by its brightness
+    pub fn event_led(&self, event: LedEvent, cmd: LedCmd) -> LedCmd {
+        cmd.scaled(match event {
+            LedEvent::Idle => self.idle_brightness,
+            LedEvent::Buzz => self.buzz_brightness,
+            LedEvent::Alert => self.alert_brightness,
+        })
+    }
+
+    pub fn wifi_settings(&self) -> WifiSettings {
+        WifiSettings {
+            channel: self.wifi_channel,
+            bssid: self.wifi_bssid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(name: &str) -> Config {
+        Config {
+            name: String::try_from(name).unwrap(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn import_keeps_the_buzzer_specific_settings() {
+        let local = Config {
+            tiebreak: Some(42),
+            wifi_channel: Some(6),
+            wifi_bssid: Some([1, 2, 3, 4, 5, 6]),
+            ..named("local")
+        };
+        let state = Config {
+            brightness: 10,
+            tiebreak: Some(7),
+            wifi_channel: Some(11),
+            wifi_bssid: Some([6, 5, 4, 3, 2, 1]),
+            ..named("remote")
+        };
+        let imported = local.import(state.export()).unwrap();
+        assert_eq!(imported.name, "remote");
+        assert_eq!(imported.brightness, 10);
+        assert_eq!(imported.tiebreak, local.tiebreak);
+        assert_eq!(imported.wifi_channel, local.wifi_channel);
+        assert_eq!(imported.wifi_bssid, local.wifi_bssid);
+    }
+
+    #[test]
+    fn import_rejects_invalid_names() {
+        let local = named("local");
+        for name in ["", "   ", "bad\nname"] {
+            assert_eq!(
+                local.import(named(name)).map(|c| c.name),
+                Err(ConfigError::InvalidName),
+                "{name:?}"
+            );
+        }
+    }
+}
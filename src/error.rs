@@ -5,8 +5,26 @@ pub enum PatternError {
     InvalidDutyCycle,
     MissingDetails,
     InvalidPatternType,
-    WavePeriodTooShort { min_ms: u64 },
+    WavePeriodTooShort {
+        min_ms: u64,
+    },
+    PeriodTooShort {
+        min_ms: u64,
+    },
+    /// Null period, or longer than the maximum
+    InvalidPeriod {
+        max_ms: u32,
+    },
     UnsupportedCommand,
+    TooManyElements {
+        max: usize,
+    },
+    /// Sequence pattern without any color
+    EmptySequence,
+    /// The color is neither a complete HSV nor a complete RGB color
+    InvalidColor,
+    /// Unknown or missing field, or field with an unexpected value
+    InvalidField,
 }
 
 impl fmt::Display for PatternError {
@@ -18,7 +36,103 @@ impl fmt::Display for PatternError {
             Self::WavePeriodTooShort { min_ms } => {
                 write!(f, "wave period too short (minimum: {min_ms}ms)")
             }
+            Self::PeriodTooShort { min_ms } => {
+                write!(f, "period too short (minimum: {min_ms}ms)")
+            }
+            Self::InvalidPeriod { max_ms } => {
+                write!(f, "invalid period (expected 1 to {max_ms}ms)")
+            }
             Self::UnsupportedCommand => write!(f, "unsupported command"),
+            Self::EmptySequence => write!(f, "sequence without colors"),
+            Self::InvalidColor => write!(f, "invalid color, expected either h/s/v or r/g/b"),
+            Self::InvalidField => write!(f, "unknown, missing or invalid pattern field"),
+            Self::TooManyElements { max } => write!(f, "too many array elements (maximum: {max})"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    InvalidJson,
+    InvalidBinary,
+    UnknownType,
+    InvalidPresetSlot,
+    InvalidTone,
+    /// Hue rotation rate not finite or too fast
+    InvalidHueShift,
+    Pattern(PatternError),
+}
+
+impl From<PatternError> for CommandError {
+    fn from(value: PatternError) -> Self {
+        Self::Pattern(value)
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidJson => write!(f, "invalid JSON message"),
+            Self::InvalidBinary => write!(f, "invalid binary message"),
+            Self::UnknownType => write!(f, "unknown command type"),
+            Self::InvalidPresetSlot => write!(f, "invalid preset slot"),
+            Self::InvalidTone => write!(f, "invalid tone frequency or duration"),
+            Self::InvalidHueShift => write!(f, "invalid hue rotation rate"),
+            Self::Pattern(e) => write!(f, "invalid pattern: {e}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    InvalidName,
+    InvalidWifiChannel,
+    EmptySsid,
+    SsidTooLong { max_len: usize },
+    PasswordTooLong { max_len: usize },
+    InvalidStaticIp,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidName => write!(f, "invalid device name"),
+            Self::InvalidWifiChannel => write!(f, "invalid wifi channel"),
+            Self::EmptySsid => write!(f, "empty wifi SSID"),
+            Self::SsidTooLong { max_len } => {
+                write!(f, "wifi SSID too long (maximum: {max_len} bytes)")
+            }
+            Self::PasswordTooLong { max_len } => {
+                write!(f, "wifi password too long (maximum: {max_len} bytes)")
+            }
+            Self::InvalidStaticIp => write!(f, "invalid static IP configuration"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebsocketError {
+    /// A message does not fit in the websocket or TCP buffers: this is a resource configuration
+    /// problem rather than a transient network one
+    OutOfBuffers,
+    Serialization,
+    Encoding,
+    /// The server tried to enable a websocket extension (e.g. compression) we do not support
+    UnsupportedExtension,
+    /// The outgoing message queue is full
+    QueueFull,
+    Write(embassy_net::tcp::Error),
+}
+
+impl fmt::Display for WebsocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfBuffers => write!(f, "out of network buffers"),
+            Self::Serialization => write!(f, "failed to serialize message"),
+            Self::Encoding => write!(f, "failed to encode websocket frame"),
+            Self::UnsupportedExtension => write!(f, "server enabled an unsupported extension"),
+            Self::QueueFull => write!(f, "message queue is full"),
+            Self::Write(e) => write!(f, "failed to write to socket: {e:?}"),
         }
     }
 }
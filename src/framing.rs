@@ -0,0 +1,304 @@
+use embedded_websocket as ws;
+use rand_core::RngCore;
+
+use crate::error::WebsocketError;
+
+/* Origin header of the handshake request, the server not checking it */
+const WEBSOCKET_ORIGIN: &str = "http://localhost:1337";
+
+pub fn map_ws_error(e: ws::Error) -> WebsocketError {
+    match e {
+        ws::Error::WriteToBufferTooSmall => WebsocketError::OutOfBuffers,
+        _ => WebsocketError::Encoding,
+    }
+}
+
+/// Build the opening handshake request in `buffer`, returning its length and the key the server
+/// answer is checked against. The key is drawn from the client RNG, so that each connection
+/// gets a new one as long as the same client is kept across reconnections.
+pub fn handshake_request<R: RngCore>(
+    client: &mut ws::WebSocketClient<R>,
+    path: &str,
+    host: &str,
+    buffer: &mut [u8],
+) -> Result<(usize, ws::WebSocketKey), WebsocketError> {
+    /* No Sec-WebSocket-Extensions header is sent, so that the server does not enable any
+     * extension (e.g. permessage-deflate compression)
+     */
+    let websocket_options = ws::WebSocketOptions {
+        path,
+        host,
+        origin: WEBSOCKET_ORIGIN,
+        sub_protocols: None,
+        additional_headers: None,
+    };
+    client
+        .client_connect(&websocket_options, buffer)
+        .map_err(map_ws_error)
+}
+
+/// Websocket data decoded by a [`MessageReader`]
+pub enum Received<'a> {
+    Ping(&'a [u8]),
+    Pong,
+    /// Complete message, with the type of its first frame
+    Message(ws::WebSocketReceiveMessageType, &'a [u8]),
+    /// Message larger than the frame buffer, dropped
+    Oversized,
+    /// Received bytes which could not be decoded, dropped along with the message being reassembled
+    Invalid(ws::Error),
+}
+
+/// Reassembles the messages received from the server. Frames may be split across reads, and
+/// messages across frames: the received bytes are decoded as far as possible, the remaining ones
+/// being kept for the next read, and the payloads are accumulated until the end of the message.
+pub struct MessageReader<'a> {
+    /* Received bytes not decoded yet, e.g. the start of a frame split across TCP segments */
+    read_buffer: &'a mut [u8],
+    frame_buffer: &'a mut [u8],
+    rx_len: usize,
+    /* Length of the read buffer already decoded */
+    offset: usize,
+    /* Length of the message being reassembled in the frame buffer */
+    msg_len: usize,
+    oversized: bool,
+}
+
+impl<'a> MessageReader<'a> {
+    pub fn new(read_buffer: &'a mut [u8], frame_buffer: &'a mut [u8]) -> Self {
+        Self {
+            read_buffer,
+            frame_buffer,
+            rx_len: 0,
+            offset: 0,
+            msg_len: 0,
+            oversized: false,
+        }
+    }
+
+    /// Drop the received data, e.g. when a new session starts
+    pub fn reset(&mut self) {
+        self.rx_len = 0;
+        self.offset = 0;
+        self.msg_len = 0;
+        self.oversized = false;
+    }
+
+    /// Free part of the read buffer, where the next received bytes go. It is never empty, since
+    /// reading into an empty buffer would look like a disconnection.
+    pub fn spare(&mut self) -> &mut [u8] {
+        &mut self.read_buffer[self.rx_len..]
+    }
+
+    /// Account for `count` bytes received in the [`spare`](Self::spare) buffer, and return them
+    pub fn received(&mut self, count: usize) -> &[u8] {
+        let start = self.rx_len;
+        self.rx_len += count;
+        &self.read_buffer[start..self.rx_len]
+    }
+
+    /// Decode the next ping, pong or complete message from the received bytes, if any
+    pub fn next<R: RngCore>(
+        &mut self,
+        client: &mut ws::WebSocketClient<R>,
+    ) -> Option<Received<'_>> {
+        while self.offset < self.rx_len {
+            let ws_frame = match client.read(
+                &self.read_buffer[self.offset..self.rx_len],
+                &mut self.frame_buffer[self.msg_len..],
+            ) {
+                Ok(ws_frame) => ws_frame,
+                Err(ws::Error::ReadFrameIncomplete) => break,
+                Err(e) => {
+                    self.offset = self.rx_len;
+                    self.msg_len = 0;
+                    self.oversized = false;
+                    return Some(Received::Invalid(e));
+                }
+            };
+            self.offset += ws_frame.len_from;
+            let payload_start = self.msg_len;
+            let payload_end = payload_start + ws_frame.len_to;
+            match ws_frame.message_type {
+                ws::WebSocketReceiveMessageType::Ping => {
+                    return Some(Received::Ping(
+                        &self.frame_buffer[payload_start..payload_end],
+                    ));
+                }
+                ws::WebSocketReceiveMessageType::Pong => return Some(Received::Pong),
+                _ => {}
+            }
+            self.msg_len = payload_end;
+            if !ws_frame.end_of_message {
+                if self.msg_len == self.frame_buffer.len() {
+                    /* Keep on decoding the message to skip it, but drop what has been received
+                     * so far
+                     */
+                    self.oversized = true;
+                    self.msg_len = 0;
+                } else if ws_frame.len_from == 0 {
+                    break;
+                }
+                continue;
+            }
+            let len = core::mem::take(&mut self.msg_len);
+            if core::mem::take(&mut self.oversized) {
+                return Some(Received::Oversized);
+            }
+            return Some(Received::Message(
+                ws_frame.message_type,
+                &self.frame_buffer[..len],
+            ));
+        }
+        self.read_buffer.copy_within(self.offset..self.rx_len, 0);
+        self.rx_len -= self.offset;
+        self.offset = 0;
+        if self.rx_len == self.read_buffer.len() {
+            /* Nothing could be decoded from a full read buffer: drop it, as there would be no room
+             * left for the next read
+             */
+            self.reset();
+            return Some(Received::Invalid(ws::Error::ReadFrameIncomplete));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic stand-in for the hardware RNG
+    struct TestRng(u32);
+
+    impl RngCore for TestRng {
+        fn next_u32(&mut self) -> u32 {
+            /* xorshift32 */
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+        fn next_u64(&mut self) -> u64 {
+            (u64::from(self.next_u32()) << 32) | u64::from(self.next_u32())
+        }
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            rand_core::impls::fill_bytes_via_next(self, dest);
+        }
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    /// Run a full session on the client: handshake, acceptance by the server and close
+    fn connect(client: &mut ws::WebSocketClient<TestRng>) -> ws::WebSocketKey {
+        let mut request = [0u8; 512];
+        let mut response = [0u8; 512];
+        let mut close = [0u8; 16];
+        let (len, key) =
+            handshake_request(client, "/ws", "192.168.4.1:8080", &mut request).unwrap();
+        let request = core::str::from_utf8(&request[..len]).unwrap();
+        assert!(request.contains(&*key), "key missing from {request}");
+        let len = ws::WebSocketServer::new_server()
+            .server_accept(&key, None, &mut response)
+            .unwrap();
+        client.client_accept(&key, &response[..len]).unwrap();
+        client
+            .close(
+                ws::WebSocketCloseStatusCode::NormalClosure,
+                None,
+                &mut close,
+            )
+            .unwrap();
+        key
+    }
+
+    /// Open client, as after a successful handshake
+    fn open_client() -> ws::WebSocketClient<TestRng> {
+        let mut client = ws::WebSocketClient::new_client(TestRng(0x1234_5678));
+        client.state = ws::WebSocketState::Open;
+        client
+    }
+
+    /// Server frames carrying the given text messages
+    fn server_frames(messages: &[&[u8]]) -> Vec<u8> {
+        let mut server = ws::WebSocketServer::new_server();
+        server.state = ws::WebSocketState::Open;
+        let mut frames = Vec::new();
+        let mut frame = [0u8; 256];
+        for msg in messages {
+            let len = server
+                .write(ws::WebSocketSendMessageType::Text, true, msg, &mut frame)
+                .unwrap();
+            frames.extend_from_slice(&frame[..len]);
+        }
+        frames
+    }
+
+    /// Feed the reader as the websocket task does, as much as the read buffer can take at a time
+    fn receive(
+        reader: &mut MessageReader<'_>,
+        client: &mut ws::WebSocketClient<TestRng>,
+        mut data: &[u8],
+    ) -> Vec<String> {
+        let mut decoded = Vec::new();
+        while !data.is_empty() {
+            let spare = reader.spare();
+            assert!(!spare.is_empty(), "no room left to read into");
+            let count = spare.len().min(data.len());
+            spare[..count].copy_from_slice(&data[..count]);
+            reader.received(count);
+            data = &data[count..];
+            while let Some(received) = reader.next(client) {
+                decoded.push(match received {
+                    Received::Ping(_) => "ping".into(),
+                    Received::Pong => "pong".into(),
+                    Received::Message(_, payload) => String::from_utf8(payload.to_vec()).unwrap(),
+                    Received::Oversized => "oversized".into(),
+                    Received::Invalid(_) => "invalid".into(),
+                });
+            }
+        }
+        decoded
+    }
+
+    #[test]
+    fn messages_are_accepted_up_to_the_frame_buffer_size() {
+        let mut read_buffer = [0u8; 24];
+        let mut frame_buffer = [0u8; 32];
+        let mut reader = MessageReader::new(&mut read_buffer, &mut frame_buffer);
+        let mut client = open_client();
+        let at_capacity = [b'a'; 32];
+        let over_capacity = [b'b'; 33];
+        let frames = server_frames(&[&at_capacity, &over_capacity, b"next"]);
+        let decoded = receive(&mut reader, &mut client, &frames);
+        assert_eq!(
+            decoded,
+            ["a".repeat(32).as_str(), "oversized", "next"],
+            "messages larger than the read buffer are reassembled"
+        );
+    }
+
+    #[test]
+    fn undecodable_full_read_buffer_is_dropped() {
+        /* Smaller than the 10 bytes header of frames larger than 64 KiB, which can never be decoded */
+        let mut read_buffer = [0u8; 8];
+        let mut frame_buffer = [0u8; 32];
+        let mut reader = MessageReader::new(&mut read_buffer, &mut frame_buffer);
+        let mut client = open_client();
+        /* Start of the header, filling the read buffer */
+        let header = [0x81, 127, 0, 0, 0, 0, 0, 1];
+        assert_eq!(receive(&mut reader, &mut client, &header), ["invalid"]);
+        let frames = server_frames(&[b"next"]);
+        assert_eq!(receive(&mut reader, &mut client, &frames), ["next"]);
+    }
+
+    #[test]
+    fn keys_differ_across_connections() {
+        let mut client = ws::WebSocketClient::new_client(TestRng(0x1234_5678));
+        let first = connect(&mut client);
+        let second = connect(&mut client);
+        assert_ne!(first, second);
+    }
+}
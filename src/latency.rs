@@ -0,0 +1,38 @@
+use embassy_time::Instant;
+use log::info;
+
+const LATENCY_WINDOW_LEN: usize = 32;
+
+/// Latency statistics over a sliding window of samples, logged every time the window is full
+pub struct LatencyStats {
+    name: &'static str,
+    samples: [u64; LATENCY_WINDOW_LEN],
+    len: usize,
+}
+
+impl LatencyStats {
+    pub const fn new(name: &'static str) -> Self {
+        LatencyStats {
+            name,
+            samples: [0; LATENCY_WINDOW_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn record(&mut self, start: Instant) {
+        self.samples[self.len] = start.elapsed().as_micros();
+        self.len += 1;
+        if self.len == LATENCY_WINDOW_LEN {
+            self.samples.sort_unstable();
+            info!(
+                "{} latency over {} samples: p50 {}us, p99 {}us, max {}us",
+                self.name,
+                LATENCY_WINDOW_LEN,
+                self.samples[LATENCY_WINDOW_LEN / 2],
+                self.samples[LATENCY_WINDOW_LEN * 99 / 100],
+                self.samples[LATENCY_WINDOW_LEN - 1]
+            );
+            self.len = 0;
+        }
+    }
+}
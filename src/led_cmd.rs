@@ -1,27 +1,120 @@
+use core::fmt;
+
 use embassy_time::Duration;
-use libm::{fabsf, fmodf};
+use libm::{fabsf, fmaxf, fminf, fmodf, logf};
 use log::warn;
-use serde::Deserialize;
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{IgnoredAny, SeqAccess, Visitor},
+};
+use serde_json_core as sj;
 use smart_leds::RGB;
 
-use crate::error::PatternError;
+use crate::error::{CommandError, PatternError};
+
+/// Number of leds on the strip
+pub const LED_COUNT: usize = 1;
+
+/// Color of each led of the strip, written as is
+pub type RawFrame = [RGB<u8>; LED_COUNT];
 
+/// Maximum number of colors of a sequence pattern
+pub const MAX_SEQUENCE_COLORS: usize = 8;
+
+/// Fastest hue rotation, in degrees per second. Faster rotations make the color flicker rather
+/// than smoothly cycle.
+pub const MAX_HUE_SHIFT_DEG_PER_SEC: f32 = 360.0;
+
+/// Color given either as HSV (`h`, `s` and `v`) or as RGB (`r`, `g` and `b`)
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct MessageLedColor {
-    h: f32,
-    s: f32,
-    v: f32,
+    h: Option<f32>,
+    s: Option<f32>,
+    v: Option<f32>,
+    r: Option<u8>,
+    g: Option<u8>,
+    b: Option<u8>,
+}
+
+impl MessageLedColor {
+    fn to_rgb(&self) -> Result<RGB<u8>, PatternError> {
+        match (self.h, self.s, self.v, self.r, self.g, self.b) {
+            (Some(h), Some(s), Some(v), None, None, None) => Ok(hsv_to_rgb(h, s, v)),
+            (None, None, None, Some(r), Some(g), Some(b)) => Ok(RGB::new(r, g, b)),
+            _ => Err(PatternError::InvalidColor),
+        }
+    }
+}
+
+const DEFAULT_PERIOD_MS: u32 = 1000;
+const DEFAULT_DUTY_CYCLE: f32 = 0.5;
+const DEFAULT_DWELL_MS: u32 = 500;
+/* Longest accepted pattern period, anything slower looks like a stuck led */
+const MAX_PERIOD_MS: u32 = 60_000;
+/* Neutral white */
+const DEFAULT_KELVIN: u16 = 4000;
+/* Color temperatures of white patterns, from candle light to daylight */
+const MIN_KELVIN: u16 = 1800;
+const MAX_KELVIN: u16 = 6500;
+
+fn default_period_ms() -> u32 {
+    DEFAULT_PERIOD_MS
 }
 
+fn default_duty_cycle() -> f32 {
+    DEFAULT_DUTY_CYCLE
+}
+
+fn default_dwell_ms() -> u32 {
+    DEFAULT_DWELL_MS
+}
+
+fn default_kelvin() -> u16 {
+    DEFAULT_KELVIN
+}
+
+/// Pattern details. Unknown fields are rejected, rather than silently falling back to the
+/// default value of a misspelled one.
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct MessageLedDetails {
+    /// Pattern duration, defaults to 0 (pattern runs until the next command)
+    #[serde(default)]
     duration_ms: u32,
+    /// Pattern period, defaults to 1s
+    #[serde(default = "default_period_ms")]
     period_ms: u32,
+    /// Duty cycle, between 0 and 1, defaults to 0.5
+    #[serde(default = "default_duty_cycle")]
     dc: f32,
-    color: MessageLedColor,
+    /// Pattern color, required by all patterns but rainbow
+    color: Option<MessageLedColor>,
+    /// Colors cycled through by sequence patterns
+    colors: Option<heapless::Vec<MessageLedColor, MAX_SEQUENCE_COLORS>>,
+    /// Time each color of a sequence is shown, defaults to 500ms
+    #[serde(default = "default_dwell_ms")]
+    dwell_ms: u32,
+    /// Color temperature of white patterns, clamped to 1800-6500K, defaults to 4000K
+    #[serde(default = "default_kelvin")]
+    kelvin: u16,
+    /// Number of periods blink, wave and strobe patterns are played before switching the led
+    /// off, defaults to none (pattern runs until its duration or the next command)
+    repeat: Option<u16>,
+    /// Shape of wave patterns, defaults to cosine
+    #[serde(default)]
+    shape: WaveShape,
+    /// Brightness ramp at the start of the pattern, defaults to 0 (no fade)
+    #[serde(default)]
+    fade_in_ms: u32,
+    /// Brightness ramp at the end of the pattern, for patterns with a duration only, defaults to
+    /// 0 (no fade)
+    #[serde(default)]
+    fade_out_ms: u32,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct MessageLedType<'a> {
     r#type: &'a str,
     details: Option<MessageLedDetails>,
@@ -33,24 +126,347 @@ pub struct MessageLedPattern<'a> {
     pattern: MessageLedType<'a>,
 }
 
+/// Raw frame message, one `[r, g, b]` entry per led. Leds missing from the frame are switched off.
+#[derive(Deserialize, Debug)]
+pub struct MessageRawFrame {
+    frame: heapless::Vec<[u8; 3], LED_COUNT>,
+}
+
+/// Number of elements of a JSON array, whatever their type
+struct ArrayLen(usize);
+
+impl<'de> Deserialize<'de> for ArrayLen {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LenVisitor;
+        impl<'de> Visitor<'de> for LenVisitor {
+            type Value = ArrayLen;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an array")
+            }
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ArrayLen, A::Error> {
+                let mut len = 0;
+                while seq.next_element::<IgnoredAny>()?.is_some() {
+                    len += 1;
+                }
+                Ok(ArrayLen(len))
+            }
+        }
+        deserializer.deserialize_seq(LenVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+struct PatternDetailsArrayLens {
+    colors: Option<ArrayLen>,
+}
+
+#[derive(Deserialize)]
+struct PatternArrayLens {
+    details: Option<PatternDetailsArrayLens>,
+}
+
+/// Length of the bounded arrays of a message
+#[derive(Deserialize)]
+struct MessageArrayLens {
+    frame: Option<ArrayLen>,
+    pattern: Option<PatternArrayLens>,
+}
+
+impl MessageArrayLens {
+    /// Length of the longest bounded array found in the message
+    fn longest(&self) -> usize {
+        let colors = self
+            .pattern
+            .as_ref()
+            .and_then(|p| p.details.as_ref())
+            .and_then(|d| d.colors.as_ref());
+        [self.frame.as_ref(), colors]
+            .into_iter()
+            .flatten()
+            .map(|ArrayLen(len)| *len)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Decode a message holding a bounded array. Since the bounded array deserialization fails like
+/// any other invalid message, the array is measured on failure to tell arrays with too many
+/// elements apart. Other errors raised by the message structs themselves, such as unknown fields,
+/// are told apart from JSON syntax errors.
+pub fn parse_bounded<'a, T: Deserialize<'a>>(msg: &'a [u8], max: usize) -> Result<T, CommandError> {
+    match sj::from_slice::<T>(msg) {
+        Ok((value, _)) => Ok(value),
+        Err(e) => match sj::from_slice::<MessageArrayLens>(msg) {
+            Ok((lens, _)) if lens.longest() > max => {
+                Err(PatternError::TooManyElements { max }.into())
+            }
+            _ if matches!(e, sj::de::Error::CustomError) => Err(PatternError::InvalidField.into()),
+            _ => Err(CommandError::InvalidJson),
+        },
+    }
+}
+
+impl From<MessageRawFrame> for LedCmd {
+    fn from(value: MessageRawFrame) -> Self {
+        let mut frame: RawFrame = [RGB::default(); LED_COUNT];
+        for (led, [r, g, b]) in frame.iter_mut().zip(value.frame) {
+            *led = RGB::new(r, g, b);
+        }
+        LedCmd::Raw(frame)
+    }
+}
+
+/// Brightness evolution over a wave period, always starting and ending dark
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WaveShape {
+    #[default]
+    #[serde(rename = "cos")]
+    Cosine,
+    /// Linear ramp up, then down
+    Triangle,
+    /// Linear ramp up, then drop
+    Sawtooth,
+}
+
+/// Brightness ramps at the start and at the end of a pattern
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Fade {
+    pub fade_in: Duration,
+    pub fade_out: Duration,
+}
+
+impl Fade {
+    pub const NONE: Fade = Fade {
+        fade_in: Duration::from_ticks(0),
+        fade_out: Duration::from_ticks(0),
+    };
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum LedCmd {
     Off,
+    Solid {
+        color: RGB<u8>,
+        duration: Duration,
+        fade: Fade,
+    },
     Blink {
         color: RGB<u8>,
         duration: Duration,
         period: Duration,
         duty_cycle: u8,
+        repeat: Option<u16>,
+        fade: Fade,
     },
     Wave {
         color: RGB<u8>,
         duration: Duration,
         period: Duration,
         duty_cycle: u8,
+        shape: WaveShape,
+        repeat: Option<u16>,
+        fade: Fade,
+    },
+    /// Fixed short flash every period, whatever its length
+    Strobe {
+        color: RGB<u8>,
+        duration: Duration,
+        period: Duration,
+        repeat: Option<u16>,
+        fade: Fade,
     },
+    /// Colors shown one after the other, each for the dwell time. Unused entries are `None`.
+    Sequence {
+        colors: [Option<RGB<u8>>; MAX_SEQUENCE_COLORS],
+        duration: Duration,
+        dwell: Duration,
+        fade: Fade,
+    },
+    /// Single lit led moving along the strip, going through all the leds every period. A single
+    /// led blinks instead.
+    Chase {
+        color: RGB<u8>,
+        duration: Duration,
+        period: Duration,
+        repeat: Option<u16>,
+        fade: Fade,
+    },
+    /// Hue sweep over the whole color wheel every period, at full saturation
+    Rainbow {
+        duration: Duration,
+        period: Duration,
+        brightness: u8,
+        fade: Fade,
+    },
+    /// White light of the given color temperature, played as a solid pattern
+    White {
+        kelvin: u16,
+        duration: Duration,
+        brightness: u8,
+        fade: Fade,
+    },
+    /// Frame written as is, bypassing brightness and hue shift, until the next command
+    Raw(RawFrame),
+    /// Breathing whose speed and brightness follow the wifi signal strength, as a site survey aid
+    RssiBreathe,
+}
+
+impl LedCmd {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LedCmd::Off => "off",
+            LedCmd::Solid { .. } => "solid",
+            LedCmd::Blink { .. } => "blink",
+            LedCmd::Wave { .. } => "wave",
+            LedCmd::Strobe { .. } => "strobe",
+            LedCmd::Sequence { .. } => "sequence",
+            LedCmd::Chase { .. } => "chase",
+            LedCmd::Rainbow { .. } => "rainbow",
+            LedCmd::White { .. } => "white",
+            LedCmd::Raw(_) => "raw",
+            LedCmd::RssiBreathe => "rssi",
+        }
+    }
+
+    /// Scale the pattern color by the given brightness, 255 leaving it unchanged. Raw frames are
+    /// written as is and never scaled.
+    pub fn scaled(self, brightness: u8) -> Self {
+        let scale = |color: RGB<u8>| {
+            let channel = |c: u8| (u16::from(c) * u16::from(brightness) / u16::from(u8::MAX)) as u8;
+            RGB::new(channel(color.r), channel(color.g), channel(color.b))
+        };
+        match self {
+            LedCmd::Solid {
+                color,
+                duration,
+                fade,
+            } => LedCmd::Solid {
+                color: scale(color),
+                duration,
+                fade,
+            },
+            LedCmd::Blink {
+                color,
+                duration,
+                period,
+                duty_cycle,
+                repeat,
+                fade,
+            } => LedCmd::Blink {
+                color: scale(color),
+                duration,
+                period,
+                duty_cycle,
+                repeat,
+                fade,
+            },
+            LedCmd::Wave {
+                color,
+                duration,
+                period,
+                duty_cycle,
+                shape,
+                repeat,
+                fade,
+            } => LedCmd::Wave {
+                color: scale(color),
+                duration,
+                period,
+                duty_cycle,
+                shape,
+                repeat,
+                fade,
+            },
+            LedCmd::Strobe {
+                color,
+                duration,
+                period,
+                repeat,
+                fade,
+            } => LedCmd::Strobe {
+                color: scale(color),
+                duration,
+                period,
+                repeat,
+                fade,
+            },
+            LedCmd::Chase {
+                color,
+                duration,
+                period,
+                repeat,
+                fade,
+            } => LedCmd::Chase {
+                color: scale(color),
+                duration,
+                period,
+                repeat,
+                fade,
+            },
+            LedCmd::Sequence {
+                colors,
+                duration,
+                dwell,
+                fade,
+            } => LedCmd::Sequence {
+                colors: colors.map(|color| color.map(scale)),
+                duration,
+                dwell,
+                fade,
+            },
+            LedCmd::Rainbow {
+                duration,
+                period,
+                brightness: value,
+                fade,
+            } => LedCmd::Rainbow {
+                duration,
+                period,
+                brightness: (u16::from(value) * u16::from(brightness) / u16::from(u8::MAX)) as u8,
+                fade,
+            },
+            LedCmd::White {
+                kelvin,
+                duration,
+                brightness: value,
+                fade,
+            } => LedCmd::White {
+                kelvin,
+                duration,
+                brightness: (u16::from(value) * u16::from(brightness) / u16::from(u8::MAX)) as u8,
+                fade,
+            },
+            LedCmd::Off | LedCmd::Raw(_) | LedCmd::RssiBreathe => self,
+        }
+    }
+
+    /// Transient commands expire on their own after their duration or their number of repeats,
+    /// while persistent ones run until they are replaced by another command
+    pub fn is_transient(&self) -> bool {
+        match self {
+            LedCmd::Off | LedCmd::Raw(_) | LedCmd::RssiBreathe => false,
+            LedCmd::Blink {
+                duration, repeat, ..
+            }
+            | LedCmd::Wave {
+                duration, repeat, ..
+            }
+            | LedCmd::Strobe {
+                duration, repeat, ..
+            }
+            | LedCmd::Chase {
+                duration, repeat, ..
+            } => duration.as_ticks() > 0 || repeat.is_some(),
+            LedCmd::Solid { duration, .. }
+            | LedCmd::Sequence { duration, .. }
+            | LedCmd::Rainbow { duration, .. }
+            | LedCmd::White { duration, .. } => duration.as_ticks() > 0,
+        }
+    }
 }
 
-fn hsv_to_rgb(h: f32, s: f32, v: f32) -> RGB<u8> {
+pub fn hsv_to_rgb(h: f32, s: f32, v: f32) -> RGB<u8> {
     let h = match h {
         h if h < 0.0 => 360.0 + h,
         _ => fmodf(h, 360.0),
@@ -79,31 +495,692 @@ fn hsv_to_rgb(h: f32, s: f32, v: f32) -> RGB<u8> {
     )
 }
 
+/// Color of a black body at the given temperature, clamped to the range of white patterns. Based on
+/// Tanner Helland's fit of the blackbody curve, below 6600K where red stays saturated.
+pub fn kelvin_to_rgb(kelvin: u16) -> RGB<u8> {
+    let t = f32::from(kelvin.clamp(MIN_KELVIN, MAX_KELVIN)) / 100.0;
+    let g = 99.4708 * logf(t) - 161.1196;
+    let b = if t <= 19.0 {
+        0.0
+    } else {
+        138.5177 * logf(t - 10.0) - 305.0448
+    };
+    RGB::new(
+        u8::MAX,
+        g.clamp(0.0, 255.0) as u8,
+        b.clamp(0.0, 255.0) as u8,
+    )
+}
+
+/// Convert a color back to HSV, returning hue in degrees and saturation/value between 0 and 1
+pub fn rgb_to_hsv(color: RGB<u8>) -> (f32, f32, f32) {
+    let r = f32::from(color.r) / 255.0;
+    let g = f32::from(color.g) / 255.0;
+    let b = f32::from(color.b) / 255.0;
+    let max = fmaxf(r, fmaxf(g, b));
+    let min = fminf(r, fminf(g, b));
+    let delta = max - min;
+
+    let h = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * fmodf((g - b) / delta + 6.0, 6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+
+    (h, s, max)
+}
+
+#[cfg(feature = "binary-commands")]
+impl LedCmd {
+    /// Decode a binary LED pattern, all fields being little endian: pattern type (u8, 0: off,
+    /// 1: blink, 2: wave, 3: solid), hue (u16, degrees), saturation (u8, 255 = 1.0), value (u8, 255 = 1.0),
+    /// duration (u32, ms), period (u32, ms) and duty cycle (u8, percent). Only the pattern type is
+    /// needed to switch the led off.
+    pub fn from_binary(data: &[u8]) -> Result<Self, PatternError> {
+        let r#type = match data.first() {
+            Some(0) => "off",
+            Some(1) => "blink",
+            Some(2) => "wave",
+            Some(3) => "solid",
+            _ => return Err(PatternError::InvalidPatternType),
+        };
+        let details = (data.len() >= 14).then(|| MessageLedDetails {
+            duration_ms: u32::from_le_bytes([data[5], data[6], data[7], data[8]]),
+            period_ms: u32::from_le_bytes([data[9], data[10], data[11], data[12]]),
+            dc: f32::from(data[13]) / 100.0,
+            color: Some(MessageLedColor {
+                h: Some(f32::from(u16::from_le_bytes([data[1], data[2]]))),
+                s: Some(f32::from(data[3]) / 255.0),
+                v: Some(f32::from(data[4]) / 255.0),
+                r: None,
+                g: None,
+                b: None,
+            }),
+            colors: None,
+            dwell_ms: DEFAULT_DWELL_MS,
+            kelvin: DEFAULT_KELVIN,
+            repeat: None,
+            shape: WaveShape::Cosine,
+            fade_in_ms: 0,
+            fade_out_ms: 0,
+        });
+        MessageLedPattern {
+            pattern: MessageLedType { r#type, details },
+        }
+        .try_into()
+    }
+}
+
+type PatternParser = fn(&MessageLedDetails, Fade) -> Result<LedCmd, PatternError>;
+
+/// Pattern types accepted from the host, besides `off` which takes no details. This table is the
+/// single source for both parsing and the capabilities advertised in the identification message.
+const PATTERN_PARSERS: [(&str, PatternParser); 8] = [
+    ("solid", parse_solid),
+    ("blink", parse_blink),
+    ("wave", parse_wave),
+    ("strobe", parse_strobe),
+    ("sequence", parse_sequence),
+    ("chase", parse_chase),
+    ("rainbow", parse_rainbow),
+    ("white", parse_white),
+];
+
+/// Names of every pattern type this firmware understands
+pub fn pattern_types() -> impl Iterator<Item = &'static str> {
+    core::iter::once("off").chain(PATTERN_PARSERS.iter().map(|(name, _)| *name))
+}
+
+fn details_color(details: &MessageLedDetails) -> Result<RGB<u8>, PatternError> {
+    details
+        .color
+        .as_ref()
+        .ok_or(PatternError::InvalidColor)?
+        .to_rgb()
+}
+
+/// Period of the patterns driven by one, the others ignoring it whatever its value
+fn details_period(details: &MessageLedDetails) -> Result<Duration, PatternError> {
+    if !(1..=MAX_PERIOD_MS).contains(&details.period_ms) {
+        return Err(PatternError::InvalidPeriod {
+            max_ms: MAX_PERIOD_MS,
+        });
+    }
+    Ok(Duration::from_millis(details.period_ms.into()))
+}
+
+fn parse_solid(details: &MessageLedDetails, fade: Fade) -> Result<LedCmd, PatternError> {
+    Ok(LedCmd::Solid {
+        color: details_color(details)?,
+        duration: Duration::from_millis(details.duration_ms.into()),
+        fade,
+    })
+}
+
+fn parse_blink(details: &MessageLedDetails, fade: Fade) -> Result<LedCmd, PatternError> {
+    Ok(LedCmd::Blink {
+        color: details_color(details)?,
+        duration: Duration::from_millis(details.duration_ms.into()),
+        period: details_period(details)?,
+        duty_cycle: (details.dc * 100.0) as u8,
+        repeat: details.repeat,
+        fade,
+    })
+}
+
+fn parse_wave(details: &MessageLedDetails, fade: Fade) -> Result<LedCmd, PatternError> {
+    Ok(LedCmd::Wave {
+        color: details_color(details)?,
+        duration: Duration::from_millis(details.duration_ms.into()),
+        period: details_period(details)?,
+        duty_cycle: (details.dc * 100.0) as u8,
+        shape: details.shape,
+        repeat: details.repeat,
+        fade,
+    })
+}
+
+fn parse_strobe(details: &MessageLedDetails, fade: Fade) -> Result<LedCmd, PatternError> {
+    Ok(LedCmd::Strobe {
+        color: details_color(details)?,
+        duration: Duration::from_millis(details.duration_ms.into()),
+        period: details_period(details)?,
+        repeat: details.repeat,
+        fade,
+    })
+}
+
+fn parse_sequence(details: &MessageLedDetails, fade: Fade) -> Result<LedCmd, PatternError> {
+    let mut colors = [None; MAX_SEQUENCE_COLORS];
+    for (slot, color) in colors.iter_mut().zip(details.colors.iter().flatten()) {
+        *slot = Some(color.to_rgb()?);
+    }
+    if colors[0].is_none() {
+        return Err(PatternError::EmptySequence);
+    }
+    Ok(LedCmd::Sequence {
+        colors,
+        duration: Duration::from_millis(details.duration_ms.into()),
+        dwell: Duration::from_millis(details.dwell_ms.into()),
+        fade,
+    })
+}
+
+fn parse_chase(details: &MessageLedDetails, fade: Fade) -> Result<LedCmd, PatternError> {
+    Ok(LedCmd::Chase {
+        color: details_color(details)?,
+        duration: Duration::from_millis(details.duration_ms.into()),
+        period: details_period(details)?,
+        repeat: details.repeat,
+        fade,
+    })
+}
+
+fn parse_rainbow(details: &MessageLedDetails, fade: Fade) -> Result<LedCmd, PatternError> {
+    Ok(LedCmd::Rainbow {
+        duration: Duration::from_millis(details.duration_ms.into()),
+        period: details_period(details)?,
+        brightness: u8::MAX,
+        fade,
+    })
+}
+
+fn parse_white(details: &MessageLedDetails, fade: Fade) -> Result<LedCmd, PatternError> {
+    Ok(LedCmd::White {
+        kelvin: details.kelvin.clamp(MIN_KELVIN, MAX_KELVIN),
+        duration: Duration::from_millis(details.duration_ms.into()),
+        brightness: u8::MAX,
+        fade,
+    })
+}
+
 impl TryFrom<MessageLedPattern<'_>> for LedCmd {
     type Error = PatternError;
     fn try_from(value: MessageLedPattern<'_>) -> Result<Self, Self::Error> {
         if value.pattern.r#type == "off" {
             return Ok(LedCmd::Off);
         }
+        let (_, parse) = PATTERN_PARSERS
+            .iter()
+            .find(|(name, _)| *name == value.pattern.r#type)
+            .ok_or(PatternError::InvalidPatternType)?;
         let details = value.pattern.details.ok_or(PatternError::MissingDetails)?;
         if !(0.0..=1.0).contains(&details.dc) {
             return Err(PatternError::InvalidDutyCycle);
         }
-        let rgb = hsv_to_rgb(details.color.h, details.color.s, details.color.v);
-        match value.pattern.r#type {
+        let fade = Fade {
+            fade_in: Duration::from_millis(details.fade_in_ms.into()),
+            fade_out: Duration::from_millis(details.fade_out_ms.into()),
+        };
+        parse(&details, fade)
+    }
+}
+
+/// Compact form of a led command, persisted in flash to be restored after a reboot. Fields not
+/// used by the command are left to their default value.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct StoredLedCmd {
+    /// Command name, as returned by `LedCmd::name`
+    t: heapless::String<8>,
+    /// Color
+    c: [u8; 3],
+    /// Duration, period (or dwell time for sequences) and fades, in ms
+    d: u32,
+    p: u32,
+    fi: u32,
+    fo: u32,
+    /// Duty cycle, in percent
+    dc: u8,
+    /// Rainbow and white brightness
+    b: u8,
+    /// White color temperature
+    k: u16,
+    r: Option<u16>,
+    sh: WaveShape,
+    /// Sequence colors
+    cs: heapless::Vec<[u8; 3], MAX_SEQUENCE_COLORS>,
+    /// Raw frame
+    f: heapless::Vec<[u8; 3], LED_COUNT>,
+}
+
+fn ms(duration: Duration) -> u32 {
+    duration.as_millis() as u32
+}
+
+fn from_ms(ms: u32) -> Duration {
+    Duration::from_millis(ms.into())
+}
+
+impl From<LedCmd> for StoredLedCmd {
+    fn from(value: LedCmd) -> Self {
+        let mut stored = StoredLedCmd::default();
+        /* Command names are shorter than the stored name capacity */
+        let _ = stored.t.push_str(value.name());
+        let color = |rgb: RGB<u8>| [rgb.r, rgb.g, rgb.b];
+        let fade = |stored: &mut StoredLedCmd, f: Fade| {
+            stored.fi = ms(f.fade_in);
+            stored.fo = ms(f.fade_out);
+        };
+        match value {
+            LedCmd::Off | LedCmd::RssiBreathe => {}
+            LedCmd::Solid {
+                color: c,
+                duration,
+                fade: f,
+            } => {
+                stored.c = color(c);
+                stored.d = ms(duration);
+                fade(&mut stored, f);
+            }
+            LedCmd::Blink {
+                color: c,
+                duration,
+                period,
+                duty_cycle,
+                repeat,
+                fade: f,
+            } => {
+                stored.c = color(c);
+                stored.d = ms(duration);
+                stored.p = ms(period);
+                stored.dc = duty_cycle;
+                stored.r = repeat;
+                fade(&mut stored, f);
+            }
+            LedCmd::Wave {
+                color: c,
+                duration,
+                period,
+                duty_cycle,
+                shape,
+                repeat,
+                fade: f,
+            } => {
+                stored.c = color(c);
+                stored.d = ms(duration);
+                stored.p = ms(period);
+                stored.dc = duty_cycle;
+                stored.sh = shape;
+                stored.r = repeat;
+                fade(&mut stored, f);
+            }
+            LedCmd::Strobe {
+                color: c,
+                duration,
+                period,
+                repeat,
+                fade: f,
+            }
+            | LedCmd::Chase {
+                color: c,
+                duration,
+                period,
+                repeat,
+                fade: f,
+            } => {
+                stored.c = color(c);
+                stored.d = ms(duration);
+                stored.p = ms(period);
+                stored.r = repeat;
+                fade(&mut stored, f);
+            }
+            LedCmd::Sequence {
+                colors,
+                duration,
+                dwell,
+                fade: f,
+            } => {
+                stored.cs = colors.into_iter().flatten().map(color).collect();
+                stored.d = ms(duration);
+                stored.p = ms(dwell);
+                fade(&mut stored, f);
+            }
+            LedCmd::Rainbow {
+                duration,
+                period,
+                brightness,
+                fade: f,
+            } => {
+                stored.d = ms(duration);
+                stored.p = ms(period);
+                stored.b = brightness;
+                fade(&mut stored, f);
+            }
+            LedCmd::White {
+                kelvin,
+                duration,
+                brightness,
+                fade: f,
+            } => {
+                stored.k = kelvin;
+                stored.d = ms(duration);
+                stored.b = brightness;
+                fade(&mut stored, f);
+            }
+            LedCmd::Raw(frame) => {
+                stored.f = frame.into_iter().map(color).collect();
+            }
+        }
+        stored
+    }
+}
+
+impl TryFrom<&StoredLedCmd> for LedCmd {
+    type Error = PatternError;
+    fn try_from(value: &StoredLedCmd) -> Result<Self, Self::Error> {
+        let [r, g, b] = value.c;
+        let color = RGB::new(r, g, b);
+        let fade = Fade {
+            fade_in: from_ms(value.fi),
+            fade_out: from_ms(value.fo),
+        };
+        match value.t.as_str() {
+            "off" => Ok(LedCmd::Off),
+            "rssi" => Ok(LedCmd::RssiBreathe),
+            "solid" => Ok(LedCmd::Solid {
+                color,
+                duration: from_ms(value.d),
+                fade,
+            }),
             "blink" => Ok(LedCmd::Blink {
-                color: rgb,
-                duration: Duration::from_millis(details.duration_ms.into()),
-                period: Duration::from_millis(details.period_ms.into()),
-                duty_cycle: (details.dc * 100.0) as u8,
+                color,
+                duration: from_ms(value.d),
+                period: from_ms(value.p),
+                duty_cycle: value.dc,
+                repeat: value.r,
+                fade,
             }),
             "wave" => Ok(LedCmd::Wave {
-                color: rgb,
-                duration: Duration::from_millis(details.duration_ms.into()),
-                period: Duration::from_millis(details.period_ms.into()),
-                duty_cycle: (details.dc * 100.0) as u8,
+                color,
+                duration: from_ms(value.d),
+                period: from_ms(value.p),
+                duty_cycle: value.dc,
+                shape: value.sh,
+                repeat: value.r,
+                fade,
+            }),
+            "strobe" => Ok(LedCmd::Strobe {
+                color,
+                duration: from_ms(value.d),
+                period: from_ms(value.p),
+                repeat: value.r,
+                fade,
             }),
+            "chase" => Ok(LedCmd::Chase {
+                color,
+                duration: from_ms(value.d),
+                period: from_ms(value.p),
+                repeat: value.r,
+                fade,
+            }),
+            "sequence" => {
+                let mut colors = [None; MAX_SEQUENCE_COLORS];
+                for (slot, [r, g, b]) in colors.iter_mut().zip(value.cs.iter().copied()) {
+                    *slot = Some(RGB::new(r, g, b));
+                }
+                Ok(LedCmd::Sequence {
+                    colors,
+                    duration: from_ms(value.d),
+                    dwell: from_ms(value.p),
+                    fade,
+                })
+            }
+            "rainbow" => Ok(LedCmd::Rainbow {
+                duration: from_ms(value.d),
+                period: from_ms(value.p),
+                brightness: value.b,
+                fade,
+            }),
+            "white" => Ok(LedCmd::White {
+                kelvin: value.k,
+                duration: from_ms(value.d),
+                brightness: value.b,
+                fade,
+            }),
+            "raw" => {
+                let mut frame: RawFrame = [RGB::default(); LED_COUNT];
+                for (led, [r, g, b]) in frame.iter_mut().zip(value.f.iter().copied()) {
+                    *led = RGB::new(r, g, b);
+                }
+                Ok(LedCmd::Raw(frame))
+            }
             _ => Err(PatternError::InvalidPatternType),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<LedCmd, PatternError> {
+        let (pattern, _) =
+            sj::from_slice::<MessageLedPattern>(json.as_bytes()).expect("valid JSON");
+        pattern.try_into()
+    }
+
+    #[test]
+    fn minimal_blink_uses_defaults() {
+        let cmd =
+            parse(r#"{"pattern":{"type":"blink","details":{"color":{"r":255,"g":0,"b":0}}}}"#);
+        let Ok(LedCmd::Blink {
+            color,
+            duration,
+            period,
+            duty_cycle,
+            repeat,
+            fade,
+        }) = cmd
+        else {
+            panic!("unexpected command {cmd:?}");
+        };
+        assert_eq!(color, RGB::new(255, 0, 0));
+        assert_eq!(duration, Duration::from_ticks(0));
+        assert_eq!(period, Duration::from_millis(DEFAULT_PERIOD_MS.into()));
+        assert_eq!(duty_cycle, 50);
+        assert_eq!(repeat, None);
+        assert_eq!(fade.fade_in, Duration::from_ticks(0));
+        assert_eq!(fade.fade_out, Duration::from_ticks(0));
+    }
+
+    #[test]
+    fn minimal_solid_runs_until_the_next_command() {
+        let cmd =
+            parse(r#"{"pattern":{"type":"solid","details":{"color":{"h":120,"s":1,"v":1}}}}"#);
+        assert!(matches!(cmd, Ok(LedCmd::Solid { color, .. }) if color == RGB::new(0, 255, 0)));
+        assert!(!cmd.unwrap().is_transient());
+    }
+
+    #[test]
+    fn repeated_patterns_are_transient() {
+        let cmd = parse(
+            r#"{"pattern":{"type":"blink","details":{"color":{"r":255,"g":0,"b":0},"repeat":3}}}"#,
+        );
+        assert!(matches!(
+            cmd,
+            Ok(LedCmd::Blink {
+                repeat: Some(3),
+                ..
+            })
+        ));
+        assert!(cmd.unwrap().is_transient());
+    }
+
+    fn solid(color: RGB<u8>) -> LedCmd {
+        LedCmd::Solid {
+            color,
+            duration: Duration::from_ticks(0),
+            fade: Fade::NONE,
+        }
+    }
+
+    #[test]
+    fn scaling_is_bounded_by_the_pattern_color() {
+        let color = RGB::new(255, 128, 1);
+        for (brightness, expected) in [
+            (u8::MAX, color),
+            (0, RGB::new(0, 0, 0)),
+            (128, RGB::new(128, 64, 0)),
+        ] {
+            assert!(matches!(
+                solid(color).scaled(brightness),
+                LedCmd::Solid { color, .. } if color == expected
+            ));
+        }
+    }
+
+    #[test]
+    fn scaling_applies_to_rainbow_brightness() {
+        let rainbow = LedCmd::Rainbow {
+            duration: Duration::from_ticks(0),
+            period: Duration::from_secs(1),
+            brightness: 200,
+            fade: Fade::NONE,
+        };
+        assert!(matches!(
+            rainbow.scaled(u8::MAX),
+            LedCmd::Rainbow {
+                brightness: 200,
+                ..
+            }
+        ));
+        assert!(matches!(
+            rainbow.scaled(0),
+            LedCmd::Rainbow { brightness: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn scaling_leaves_raw_frames_untouched() {
+        let frame = [RGB::new(10, 20, 30); LED_COUNT];
+        assert!(matches!(LedCmd::Raw(frame).scaled(0), LedCmd::Raw(f) if f == frame));
+    }
+
+    #[test]
+    fn off_needs_no_details() {
+        assert!(matches!(
+            parse(r#"{"pattern":{"type":"off"}}"#),
+            Ok(LedCmd::Off)
+        ));
+    }
+
+    #[test]
+    fn color_is_required() {
+        assert_eq!(
+            parse(r#"{"pattern":{"type":"wave","details":{}}}"#).err(),
+            Some(PatternError::InvalidColor)
+        );
+    }
+
+    #[test]
+    fn details_are_required() {
+        assert_eq!(
+            parse(r#"{"pattern":{"type":"solid"}}"#).err(),
+            Some(PatternError::MissingDetails)
+        );
+    }
+
+    fn sequence_message(len: usize) -> String {
+        let colors = vec![r#"{"r":1,"g":2,"b":3}"#; len].join(",");
+        format!(r#"{{"pattern":{{"type":"sequence","details":{{"colors":[{colors}]}}}}}}"#)
+    }
+
+    fn frame_message(len: usize) -> String {
+        format!(r#"{{"frame":[{}]}}"#, vec!["[1,2,3]"; len].join(","))
+    }
+
+    #[test]
+    fn sequence_at_capacity_is_accepted() {
+        let msg = sequence_message(MAX_SEQUENCE_COLORS);
+        let pattern = parse_bounded::<MessageLedPattern>(msg.as_bytes(), MAX_SEQUENCE_COLORS);
+        assert!(matches!(
+            pattern.map(LedCmd::try_from),
+            Ok(Ok(LedCmd::Sequence { colors, .. })) if colors.iter().all(Option::is_some)
+        ));
+    }
+
+    #[test]
+    fn oversized_sequences_are_reported_as_such() {
+        for len in [MAX_SEQUENCE_COLORS + 1, 10 * MAX_SEQUENCE_COLORS] {
+            let msg = sequence_message(len);
+            assert_eq!(
+                parse_bounded::<MessageLedPattern>(msg.as_bytes(), MAX_SEQUENCE_COLORS).err(),
+                Some(
+                    PatternError::TooManyElements {
+                        max: MAX_SEQUENCE_COLORS
+                    }
+                    .into()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn frame_at_capacity_is_accepted() {
+        let msg = frame_message(LED_COUNT);
+        let frame = parse_bounded::<MessageRawFrame>(msg.as_bytes(), LED_COUNT);
+        assert!(matches!(
+            frame.map(LedCmd::from),
+            Ok(LedCmd::Raw(f)) if f == [RGB::new(1, 2, 3); LED_COUNT]
+        ));
+    }
+
+    #[test]
+    fn oversized_frames_are_reported_as_such() {
+        for len in [LED_COUNT + 1, 100 * LED_COUNT] {
+            let msg = frame_message(len);
+            assert_eq!(
+                parse_bounded::<MessageRawFrame>(msg.as_bytes(), LED_COUNT).err(),
+                Some(PatternError::TooManyElements { max: LED_COUNT }.into())
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_messages_are_not_reported_as_oversized() {
+        let msg = sequence_message(MAX_SEQUENCE_COLORS);
+        let truncated = &msg.as_bytes()[..msg.len() - 1];
+        assert_eq!(
+            parse_bounded::<MessageLedPattern>(truncated, MAX_SEQUENCE_COLORS).err(),
+            Some(CommandError::InvalidJson)
+        );
+    }
+
+    #[test]
+    fn period_is_bounded_for_period_driven_patterns() {
+        for r#type in ["blink", "wave", "strobe", "chase", "rainbow"] {
+            for (period_ms, valid) in [(0, false), (1, true), (60_000, true), (60_001, false)] {
+                let cmd = parse(&format!(
+                    r#"{{"pattern":{{"type":"{type}","details":{{"color":{{"r":255,"g":0,"b":0}},"period_ms":{period_ms}}}}}}}"#
+                ));
+                let expected = Err(PatternError::InvalidPeriod {
+                    max_ms: MAX_PERIOD_MS,
+                });
+                assert_eq!(cmd.is_ok(), valid, "{type} with a {period_ms}ms period");
+                if !valid {
+                    assert_eq!(cmd.map(|_| ()), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn period_is_ignored_by_other_patterns() {
+        for (r#type, details) in [
+            ("solid", r#""color":{"r":255,"g":0,"b":0}"#),
+            ("sequence", r#""colors":[{"r":255,"g":0,"b":0}]"#),
+            ("white", r#""kelvin":2700"#),
+        ] {
+            let cmd = parse(&format!(
+                r#"{{"pattern":{{"type":"{type}","details":{{{details},"period_ms":0}}}}}}"#
+            ));
+            assert!(cmd.is_ok(), "{type} with a null period: {cmd:?}");
+        }
+    }
+}
@@ -1,12 +1,27 @@
+use core::cell::Cell;
 use core::f64::consts::PI;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
 
-use crate::error::PatternError;
-use crate::led_cmd::LedCmd;
+use crate::led_cmd::{
+    Fade, LED_COUNT, LedCmd, MAX_HUE_SHIFT_DEG_PER_SEC, RawFrame, WaveShape, hsv_to_rgb, rgb_to_hsv,
+};
+#[cfg(feature = "debug-commands")]
+use crate::led_pattern::TableDump;
+use crate::led_pattern::{
+    MIN_TICK_PERIOD_MS, MIN_WAVE_PERIOD_MS, PatternProperties, SubPatternProperties,
+};
+use crate::led_timing;
+use crate::network;
+use crate::watchdog::{self, HEARTBEAT_SECS, Subsystem};
 use embassy_executor::Spawner;
 use embassy_futures::select::{Either, select};
 use embassy_sync::{
-    blocking_mutex::raw::NoopRawMutex,
+    blocking_mutex::{
+        Mutex,
+        raw::{CriticalSectionRawMutex, NoopRawMutex},
+    },
     channel::{Channel, Receiver, Sender},
+    signal::Signal,
 };
 use embassy_time::{Duration, Instant, Timer};
 use esp_hal::{
@@ -14,111 +29,83 @@ use esp_hal::{
     rmt::{PulseCode, Rmt},
 };
 use esp_hal_smartled::{self as sl, SmartLedsAdapterAsync, smart_led_buffer};
-use libm::{cos, trunc};
+use libm::{cos, fmodf};
 use log::{error, info};
-use smart_leds::{RGB, SmartLedsWriteAsync, brightness};
+use smart_leds::{RGB, SmartLedsWriteAsync};
 use static_cell::StaticCell;
 
-const MAX_BRIGHTNESS_TABLE_LEN: usize = 70;
-const WAVE_TICK_PERIOD_MS: u64 = 30;
-const MIN_WAVE_PERIOD_MS: u64 = MAX_BRIGHTNESS_TABLE_LEN as u64 * WAVE_TICK_PERIOD_MS;
-const MAX_BRIGHTNESS: u32 = 255;
+/* One RMT pulse per bit, 24 bits per led, plus the end marker */
+const ADAPTER_BUFFER_LEN: usize = LED_COUNT * 24 + 1;
+/* Refresh period while fading in or out */
+const FADE_TICK_PERIOD_MS: u64 = 20;
+/* Transition from the last written colors to the first ones of a new pattern or frame */
+const CROSSFADE_MS: u64 = 200;
+/* Red, green, blue and white are shown in turn on boot, so that assemblers can check the led wiring
+ * visually. Clear SELF_TEST to skip it outside of factory testing.
+ */
+const SELF_TEST: bool = true;
+const SELF_TEST_STEP_MS: u64 = 300;
+const SELF_TEST_LEVEL: u8 = 64;
 
-pub struct Led {
-    cmd_channel: Sender<'static, NoopRawMutex, LedCmd, 1>,
-}
-
-#[derive(Copy, Clone, Default, Debug)]
-struct SubPatternProperties {
-    brightness: u8,
-    duration: Duration,
-}
+/* Ceiling of the brightness patterns drive the leds with, as the buzzers sit right in front of the
+ * players, and to keep the power draw within budget. The off state is not affected.
+ */
+const MAX_OUTPUT_BRIGHTNESS: u8 = 180;
+/* Gamma correction of the output brightness, so that fades and waves look perceptually linear.
+ * Can be disabled to compare with the raw linear output.
+ */
+const GAMMA_CORRECTION: bool = true;
+/* round((i / 255)^2.2 * 255) */
+const GAMMA_TABLE: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2,
+    3, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 6, 6, 6, 6, 7, 7, 7, 8, 8, 8, 9, 9, 9, 10, 10, 11, 11,
+    11, 12, 12, 13, 13, 13, 14, 14, 15, 15, 16, 16, 17, 17, 18, 18, 19, 19, 20, 20, 21, 22, 22, 23,
+    23, 24, 25, 25, 26, 26, 27, 28, 28, 29, 30, 30, 31, 32, 33, 33, 34, 35, 35, 36, 37, 38, 39, 39,
+    40, 41, 42, 43, 43, 44, 45, 46, 47, 48, 49, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61,
+    62, 63, 64, 65, 66, 67, 68, 69, 70, 71, 73, 74, 75, 76, 77, 78, 79, 81, 82, 83, 84, 85, 87, 88,
+    89, 90, 91, 93, 94, 95, 97, 98, 99, 100, 102, 103, 105, 106, 107, 109, 110, 111, 113, 114, 116,
+    117, 119, 120, 121, 123, 124, 126, 127, 129, 130, 132, 133, 135, 137, 138, 140, 141, 143, 145,
+    146, 148, 149, 151, 153, 154, 156, 158, 159, 161, 163, 165, 166, 168, 170, 172, 173, 175, 177,
+    179, 181, 182, 184, 186, 188, 190, 192, 194, 196, 197, 199, 201, 203, 205, 207, 209, 211, 213,
+    215, 217, 219, 221, 223, 225, 227, 229, 231, 234, 236, 238, 240, 242, 244, 246, 248, 251, 253,
+    255,
+];
 
-#[derive(Debug)]
-struct PatternProperties {
-    color: RGB<u8>,
-    duration: Duration,
-    brightness_table: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN],
-    brightness_table_len: usize,
-}
+/* Signal strength range mapped to the RSSI breathing, from slow and dim to fast and bright */
+const RSSI_WEAK_DBM: i8 = -90;
+const RSSI_STRONG_DBM: i8 = -40;
+const RSSI_WEAK_PERIOD_MS: u64 = 6000;
+const RSSI_WEAK_VALUE: f32 = 0.1;
+const RSSI_HUE: f32 = 180.0;
 
-fn compute_wave_table(period: Duration) -> [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] {
-    let mut result: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] =
-        [Default::default(); MAX_BRIGHTNESS_TABLE_LEN];
+/* Global brightness scale applied on top of every pattern, set from the device configuration */
+static OUTPUT_BRIGHTNESS: AtomicU8 = AtomicU8::new(u8::MAX);
+static LED_ENABLED: AtomicBool = AtomicBool::new(true);
+/* Hue rotation applied to the running pattern color, stored as f32 bits, in degrees per second */
+static HUE_SHIFT: AtomicU32 = AtomicU32::new(0);
+/* Raised when the led goes off on a command, or once a pattern completes, but not when an invalid
+ * command is dropped
+ */
+static LED_IDLE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/* Pattern played when a transient pattern expires and no persistent one runs, either because none
+ * has been received yet or because the led was last switched off
+ */
+static IDLE_CMD: Mutex<CriticalSectionRawMutex, Cell<LedCmd>> = Mutex::new(Cell::new(LedCmd::Off));
+/* Name of the pattern run by the led task */
+static CURRENT_PATTERN: Mutex<CriticalSectionRawMutex, Cell<&'static str>> =
+    Mutex::new(Cell::new("off"));
 
-    for (index, subpattern) in result.iter_mut().enumerate() {
-        let value: f64 = MAX_BRIGHTNESS as f64 / 2.0
-            * (1.0
-                + cos(PI * (2.0 * index as f64 - MAX_BRIGHTNESS_TABLE_LEN as f64)
-                    / MAX_BRIGHTNESS_TABLE_LEN as f64));
-        subpattern.brightness = trunc(value) as u8;
-        subpattern.duration = Duration::from_millis(WAVE_TICK_PERIOD_MS);
-    }
+#[cfg(feature = "debug-commands")]
+static CURRENT_TABLE: Mutex<CriticalSectionRawMutex, core::cell::RefCell<TableDump>> =
+    Mutex::new(core::cell::RefCell::new(heapless::Vec::new()));
 
-    /* Make sure that the last value is 0, and make it last long enough so that it match the target
-     * period
-     */
-    result[MAX_BRIGHTNESS_TABLE_LEN - 1].brightness = 0;
-    result[MAX_BRIGHTNESS_TABLE_LEN - 1].duration =
-        period - Duration::from_millis(MAX_BRIGHTNESS_TABLE_LEN as u64 * WAVE_TICK_PERIOD_MS);
-
-    result
-}
-
-impl PatternProperties {
-    fn new(value: &LedCmd) -> Result<Self, PatternError> {
-        match *value {
-            LedCmd::Blink {
-                color: c,
-                duration: d,
-                period: p,
-                duty_cycle: dc,
-            } => {
-                if dc > 100 {
-                    return Err(PatternError::InvalidDutyCycle);
-                }
-                let mut table: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] =
-                    [Default::default(); MAX_BRIGHTNESS_TABLE_LEN];
-                table[0].brightness = 100;
-                table[0].duration = p * dc.into() / 100;
-                table[1].brightness = 0;
-                table[1].duration = p - table[0].duration;
-                Ok(PatternProperties {
-                    color: c,
-                    duration: d,
-                    brightness_table: table,
-                    brightness_table_len: 2,
-                })
-            }
-            LedCmd::Wave {
-                color: c,
-                duration: d,
-                period: p,
-                duty_cycle: dc,
-            } => {
-                if dc > 100 {
-                    return Err(PatternError::InvalidDutyCycle);
-                }
-                if p < Duration::from_millis(MIN_WAVE_PERIOD_MS) {
-                    return Err(PatternError::WavePeriodTooShort {
-                        min_ms: MIN_WAVE_PERIOD_MS,
-                    });
-                }
-                let table = compute_wave_table(p);
-                Ok(PatternProperties {
-                    color: c,
-                    duration: d,
-                    brightness_table: table,
-                    brightness_table_len: MAX_BRIGHTNESS_TABLE_LEN,
-                })
-            }
-            _ => Err(PatternError::UnsupportedCommand),
-        }
-    }
+pub struct Led {
+    cmd_channel: Sender<'static, NoopRawMutex, LedCmd, 1>,
+    last_cmd: LedCmd,
 }
 
 static LED_CMD_CHANNEL: StaticCell<Channel<NoopRawMutex, LedCmd, 1>> = StaticCell::new();
-static ADAPTER_BUFFER: StaticCell<[PulseCode; 25]> = StaticCell::new();
+static ADAPTER_BUFFER: StaticCell<[PulseCode; ADAPTER_BUFFER_LEN]> = StaticCell::new();
 
 impl Led {
     pub fn new<O>(spawner: &Spawner, rmt: Rmt<'static, esp_hal::Async>, gpio: O) -> Self
@@ -126,7 +113,8 @@ impl Led {
         O: PeripheralOutput<'static>,
     {
         let channel: &'static mut _ = LED_CMD_CHANNEL.init(Channel::new());
-        let buffer: &'static mut _ = ADAPTER_BUFFER.init(smart_led_buffer!(1));
+        led_timing::check_timing(&led_timing::selected_profile());
+        let buffer: &'static mut _ = ADAPTER_BUFFER.init(smart_led_buffer!(LED_COUNT));
         spawner.spawn(
             led_task(
                 sl::SmartLedsAdapterAsync::new(rmt.channel0, gpio, buffer),
@@ -136,96 +124,424 @@ impl Led {
         );
         Led {
             cmd_channel: channel.sender(),
+            last_cmd: LedCmd::Off,
         }
     }
 
     pub async fn set(&mut self, cmd: LedCmd) {
+        self.last_cmd = cmd;
         self.cmd_channel.send(cmd).await
     }
+
+    /// Enable or disable the LED. While disabled, the LED stays off and all commands are ignored;
+    /// re-enabling it resumes the last received command.
+    pub async fn set_enabled(&mut self, enabled: bool) {
+        if LED_ENABLED.load(Ordering::Relaxed) == enabled {
+            return;
+        }
+        LED_ENABLED.store(enabled, Ordering::Relaxed);
+        self.cmd_channel.send(self.last_cmd).await
+    }
+
+    /// Get the brightness table of the running pattern
+    #[cfg(feature = "debug-commands")]
+    pub fn current_table(&self) -> TableDump {
+        CURRENT_TABLE.lock(|table| table.borrow().clone())
+    }
+
+    /// Name of the pattern currently shown, which may differ from the last command once a
+    /// transient pattern has expired
+    pub fn current_pattern(&self) -> &'static str {
+        CURRENT_PATTERN.lock(Cell::get)
+    }
+
+    /// Wait for the led to go off, following either an off command or the end of a pattern
+    pub async fn wait_for_idle(&self) {
+        LED_IDLE.wait().await
+    }
+
+    pub fn set_idle(&mut self, cmd: LedCmd) {
+        IDLE_CMD.lock(|idle| idle.set(cmd));
+    }
+
+    /// Continuously rotate the hue of the running pattern color, 0 stopping the rotation
+    pub fn set_hue_shift(&mut self, degrees_per_sec: f32) {
+        /* Rates are checked when parsing the command */
+        debug_assert!(degrees_per_sec.abs() <= MAX_HUE_SHIFT_DEG_PER_SEC);
+        HUE_SHIFT.store(degrees_per_sec.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) {
+        OUTPUT_BRIGHTNESS.store(brightness, Ordering::Relaxed);
+    }
+}
+
+/// Wait for a new command, until the deadline if any, while reporting liveness to the watchdog
+/// even while waiting for a long pattern step or for a new command
+async fn wait_for_cmd(
+    cmd_channel: &Receiver<'static, NoopRawMutex, LedCmd, 1>,
+    deadline: Option<Instant>,
+) -> Option<LedCmd> {
+    loop {
+        watchdog::feed(Subsystem::Led);
+        let next_beat = Instant::now() + Duration::from_secs(HEARTBEAT_SECS);
+        let wake = deadline.map_or(next_beat, |d| d.min(next_beat));
+        match select(cmd_channel.receive(), Timer::at(wake)).await {
+            Either::First(cmd) => return Some(cmd),
+            Either::Second(_) if deadline.is_some_and(|d| wake >= d) => return None,
+            Either::Second(_) => {}
+        }
+    }
+}
+
+/// Final brightness written to the leds: gamma corrected, then scaled to the output ceiling
+fn output_level(level: u8) -> u8 {
+    let level = if GAMMA_CORRECTION {
+        GAMMA_TABLE[usize::from(level)]
+    } else {
+        level
+    };
+    (u16::from(level) * u16::from(MAX_OUTPUT_BRIGHTNESS) / u16::from(u8::MAX)) as u8
+}
+
+/// Color scaled by the given brightness, the same way as `smart_leds::brightness`
+fn dimmed(color: RGB<u8>, level: u8) -> RawFrame {
+    let channel = |c: u8| (u16::from(c) * (u16::from(level) + 1) / 256) as u8;
+    [RGB::new(channel(color.r), channel(color.g), channel(color.b)); LED_COUNT]
+}
+
+/// Write a frame to the strip, keeping track of it as the last written one
+async fn write_frame(
+    controller: &mut SmartLedsAdapterAsync<'static, ADAPTER_BUFFER_LEN>,
+    last: &mut RawFrame,
+    frame: RawFrame,
+) {
+    if let Err(e) = controller.write(frame.into_iter()).await {
+        error!("Failed to set led: {:?}", e);
+    }
+    *last = frame;
+}
+
+/// Show each primary color then white in turn, at a safe brightness, and turn the led off
+async fn self_test(
+    controller: &mut SmartLedsAdapterAsync<'static, ADAPTER_BUFFER_LEN>,
+    last: &mut RawFrame,
+) {
+    info!("Running led self-test");
+    for color in [
+        RGB::new(u8::MAX, 0, 0),
+        RGB::new(0, u8::MAX, 0),
+        RGB::new(0, 0, u8::MAX),
+        RGB::new(u8::MAX, u8::MAX, u8::MAX),
+    ] {
+        write_frame(controller, last, dimmed(color, SELF_TEST_LEVEL)).await;
+        Timer::after_millis(SELF_TEST_STEP_MS).await;
+    }
+    write_frame(controller, last, [RGB::default(); LED_COUNT]).await;
+}
+
+/// Interpolate linearly from the last written frame to the given one, so that switching patterns
+/// does not cut hard. Returns the command received in the meantime, if any.
+async fn crossfade(
+    controller: &mut SmartLedsAdapterAsync<'static, ADAPTER_BUFFER_LEN>,
+    cmd_channel: &Receiver<'static, NoopRawMutex, LedCmd, 1>,
+    last: &mut RawFrame,
+    to: RawFrame,
+) -> Option<LedCmd> {
+    let from = *last;
+    if from == to {
+        return None;
+    }
+    let start = Instant::now();
+    let mix = |a: u8, b: u8, t: f32| (f32::from(a) + (f32::from(b) - f32::from(a)) * t) as u8;
+    loop {
+        let t = (start.elapsed().as_micros() as f32 / (CROSSFADE_MS * 1000) as f32).min(1.0);
+        let mut frame = to;
+        for (led, (a, b)) in frame.iter_mut().zip(from.iter().zip(to.iter())) {
+            *led = RGB::new(mix(a.r, b.r, t), mix(a.g, b.g, t), mix(a.b, b.b, t));
+        }
+        write_frame(controller, last, frame).await;
+        if t >= 1.0 {
+            return None;
+        }
+        let wake = Instant::now() + Duration::from_millis(FADE_TICK_PERIOD_MS);
+        if let Some(cmd) = wait_for_cmd(cmd_channel, Some(wake)).await {
+            return Some(cmd);
+        }
+    }
+}
+
+/// Frame of a pattern step at the given time of the pattern
+fn pattern_frame(
+    pattern: &PatternProperties,
+    subpattern: &SubPatternProperties,
+    elapsed: Duration,
+) -> RawFrame {
+    let envelope = fade_envelope(&pattern.fade, elapsed, pattern.duration);
+    let level = f32::from(subpattern.brightness)
+        * f32::from(OUTPUT_BRIGHTNESS.load(Ordering::Relaxed))
+        / f32::from(u8::MAX)
+        * envelope;
+    let hue_shift = f32::from_bits(HUE_SHIFT.load(Ordering::Relaxed));
+    let base = subpattern.color.unwrap_or(pattern.color);
+    let color = if hue_shift == 0.0 {
+        base
+    } else {
+        let (h, s, v) = rgb_to_hsv(base);
+        let elapsed = elapsed.as_millis() as f32 / 1000.0;
+        hsv_to_rgb(fmodf(h + hue_shift * elapsed, 360.0), s, v)
+    };
+    let mut frame = dimmed(color, output_level(level as u8));
+    if let Some(pixel) = subpattern.pixel {
+        for (index, led) in frame.iter_mut().enumerate() {
+            if index != pixel {
+                *led = RGB::default();
+            }
+        }
+    }
+    frame
 }
 
-async fn execute_off(
-    controller: &mut SmartLedsAdapterAsync<'static, 25>,
+/// Write a fixed frame to the strip, as is, and hold it until the next command
+async fn execute_frame(
+    controller: &mut SmartLedsAdapterAsync<'static, ADAPTER_BUFFER_LEN>,
     cmd_channel: &Receiver<'static, NoopRawMutex, LedCmd, 1>,
+    last: &mut RawFrame,
+    frame: RawFrame,
 ) -> LedCmd {
-    if let Err(e) = controller
-        .write(brightness([RGB::new(0, 0, 0)].into_iter(), 0))
-        .await
-    {
-        error!("Failed to set led off: {:?}", e);
+    if let Some(cmd) = crossfade(controller, cmd_channel, last, frame).await {
+        return cmd;
+    }
+    write_frame(controller, last, frame).await;
+    #[cfg(feature = "debug-commands")]
+    CURRENT_TABLE.lock(|table| table.borrow_mut().clear());
+    loop {
+        if let Some(cmd) = wait_for_cmd(cmd_channel, None).await {
+            return cmd;
+        }
     }
-    cmd_channel.receive().await
 }
 
+/// Play a pattern until the next command, which is returned, or until it completes
 async fn execute_pattern(
-    controller: &mut SmartLedsAdapterAsync<'static, 25>,
+    controller: &mut SmartLedsAdapterAsync<'static, ADAPTER_BUFFER_LEN>,
     cmd_channel: &Receiver<'static, NoopRawMutex, LedCmd, 1>,
+    last: &mut RawFrame,
     pattern: PatternProperties,
-) -> LedCmd {
+) -> Option<LedCmd> {
     let mut value = pattern.brightness_table[..pattern.brightness_table_len]
         .iter()
         .cycle();
+    #[cfg(feature = "debug-commands")]
+    CURRENT_TABLE.lock(|table| {
+        let mut table = table.borrow_mut();
+        table.clear();
+        for entry in &pattern.brightness_table[..pattern.brightness_table_len] {
+            let _ = table.push((entry.brightness, entry.duration.as_millis() as u32));
+        }
+    });
+
+    let first = pattern_frame(
+        &pattern,
+        &pattern.brightness_table[0],
+        Duration::from_ticks(0),
+    );
+    if let Some(cmd) = crossfade(controller, cmd_channel, last, first).await {
+        return Some(cmd);
+    }
     let start = Instant::now();
 
+    /* Transient patterns end exactly after their duration, even in the middle of a step */
+    let end = (pattern.duration.as_ticks() > 0).then(|| start + pattern.duration);
+
+    /* Steps played so far, to stop after the requested number of runs through the table */
+    let mut steps: usize = 0;
     loop {
+        if let Some(repeat) = pattern.repeat
+            && steps >= usize::from(repeat) * pattern.brightness_table_len
+        {
+            info!("Pattern repeated {repeat} times");
+            return None;
+        }
+        steps += 1;
         let subpattern = value
             .next()
             .expect("brightness_table_len > 0 guarantees cycle never ends");
-        if let Err(e) = controller
-            .write(brightness(
-                [pattern.color].into_iter(),
-                subpattern.brightness,
-            ))
-            .await
-        {
-            error!("Failed to set led: {:?}", e);
-        }
-        match select(cmd_channel.receive(), Timer::after(subpattern.duration)).await {
-            Either::First(x) => return x,
-            _ => {
-                if pattern.duration.as_millis() > 0
-                    && Instant::now().duration_since(start) > pattern.duration
-                {
-                    info!("Pattern expired");
-                    return LedCmd::Off;
-                }
+        let step_end = Instant::now()
+            + subpattern
+                .duration
+                .max(Duration::from_millis(MIN_TICK_PERIOD_MS));
+        /* A step is refreshed several times while fading, to ramp its brightness smoothly */
+        loop {
+            let elapsed = start.elapsed();
+            write_frame(
+                controller,
+                last,
+                pattern_frame(&pattern, subpattern, elapsed),
+            )
+            .await;
+            let mut wake = step_end;
+            if is_fading(&pattern.fade, elapsed, pattern.duration) {
+                wake = wake.min(Instant::now() + Duration::from_millis(FADE_TICK_PERIOD_MS));
+            }
+            if let Some(end) = end {
+                wake = wake.min(end);
+            }
+            if let Some(x) = wait_for_cmd(cmd_channel, Some(wake)).await {
+                return Some(x);
+            }
+            if end.is_some_and(|end| Instant::now() >= end) {
+                info!("Pattern expired");
+                return None;
+            }
+            if Instant::now() >= step_end {
+                break;
             }
         }
     }
 }
 
+/// Cosine ramp from 0 to 1 over the given length
+fn ramp(t: Duration, len: Duration) -> f32 {
+    if t >= len {
+        return 1.0;
+    }
+    let x = t.as_micros() as f64 / len.as_micros() as f64;
+    ((1.0 - cos(PI * x)) / 2.0) as f32
+}
+
+/// Brightness factor, between 0 and 1, applied by the fades at the given time of a pattern. Fade
+/// outs only apply to patterns with a duration, which reach 0 exactly at their end.
+fn fade_envelope(fade: &Fade, elapsed: Duration, duration: Duration) -> f32 {
+    let fade_in = ramp(elapsed, fade.fade_in);
+    let fade_out = if duration.as_ticks() > 0 {
+        ramp(
+            duration.checked_sub(elapsed).unwrap_or_default(),
+            fade.fade_out,
+        )
+    } else {
+        1.0
+    };
+    fade_in.min(fade_out)
+}
+
+fn is_fading(fade: &Fade, elapsed: Duration, duration: Duration) -> bool {
+    elapsed < fade.fade_in
+        || (duration.as_ticks() > 0
+            && duration.checked_sub(elapsed).unwrap_or_default() < fade.fade_out)
+}
+
+/// Single breath whose period and brightness reflect the current signal strength
+fn rssi_breath() -> LedCmd {
+    let strength = network::rssi().map_or(0.0, |rssi| {
+        f32::from(rssi.clamp(RSSI_WEAK_DBM, RSSI_STRONG_DBM) - RSSI_WEAK_DBM)
+            / f32::from(RSSI_STRONG_DBM - RSSI_WEAK_DBM)
+    });
+    let period_ms =
+        RSSI_WEAK_PERIOD_MS - ((RSSI_WEAK_PERIOD_MS - MIN_WAVE_PERIOD_MS) as f32 * strength) as u64;
+    let value = RSSI_WEAK_VALUE + (1.0 - RSSI_WEAK_VALUE) * strength;
+    LedCmd::Wave {
+        color: hsv_to_rgb(RSSI_HUE, 1.0, value),
+        /* Expire right before the end of the breath, so that the next one uses a fresh RSSI */
+        duration: Duration::from_millis(period_ms - 1),
+        period: Duration::from_millis(period_ms),
+        duty_cycle: 50,
+        shape: WaveShape::Cosine,
+        repeat: None,
+        fade: Fade::NONE,
+    }
+}
+
 #[embassy_executor::task]
 async fn led_task(
-    mut controller: SmartLedsAdapterAsync<'static, 25>,
+    mut controller: SmartLedsAdapterAsync<'static, ADAPTER_BUFFER_LEN>,
     cmd_channel: Receiver<'static, NoopRawMutex, LedCmd, 1>,
 ) {
-    if let Err(e) = controller
-        .write(brightness([RGB::new(0, 0, 0)].into_iter(), 0))
-        .await
-    {
-        error!("Failed to initialize led to off state: {:?}", e);
+    /* Last frame written to the strip, the starting point of crossfades */
+    let mut last: RawFrame = [RGB::default(); LED_COUNT];
+    /* Every led of the strip may still be lit from before a reset */
+    write_frame(&mut controller, &mut last, [RGB::default(); LED_COUNT]).await;
+    if SELF_TEST && LED_ENABLED.load(Ordering::Relaxed) {
+        self_test(&mut controller, &mut last).await;
     }
-    let mut cmd = cmd_channel.receive().await;
+    let mut cmd = loop {
+        if let Some(cmd) = wait_for_cmd(&cmd_channel, None).await {
+            break cmd;
+        }
+    };
+    /* Last valid persistent command, restored once a transient one expires. Switching the led off
+     * clears it, so that the idle pattern is shown rather than an off led.
+     */
+    let mut persistent: Option<LedCmd> = None;
+    /* Whether the led going off is worth reporting: not when it stands in for an invalid command,
+     * nor when it follows a pattern end which has already been reported
+     */
+    let mut report_off = true;
     loop {
+        if !LED_ENABLED.load(Ordering::Relaxed) {
+            cmd = LedCmd::Off;
+        }
+        CURRENT_PATTERN.lock(|current| current.set(cmd.name()));
+        let report = core::mem::replace(&mut report_off, true);
         match cmd {
-            LedCmd::Blink { .. } => match PatternProperties::new(&cmd) {
-                Ok(pattern) => {
-                    info!("Starting blink pattern");
-                    cmd = execute_pattern(&mut controller, &cmd_channel, pattern).await;
+            LedCmd::Off => {
+                info!("Shutting led off");
+                if report && LED_ENABLED.load(Ordering::Relaxed) {
+                    LED_IDLE.signal(());
                 }
-                Err(e) => error!("Received invalid blink command: {e}"),
-            },
-            LedCmd::Wave { .. } => match PatternProperties::new(&cmd) {
+                persistent = None;
+                cmd = execute_frame(
+                    &mut controller,
+                    &cmd_channel,
+                    &mut last,
+                    [RGB::default(); LED_COUNT],
+                )
+                .await
+            }
+            LedCmd::Raw(frame) => {
+                info!("Writing raw led frame");
+                persistent = Some(cmd);
+                cmd = execute_frame(&mut controller, &cmd_channel, &mut last, frame).await
+            }
+            LedCmd::RssiBreathe => {
+                persistent = Some(cmd);
+                /* Each breath falls back to the RSSI mode, until any other command is received */
+                cmd = match PatternProperties::new(&rssi_breath()) {
+                    Ok(pattern) => {
+                        execute_pattern(&mut controller, &cmd_channel, &mut last, pattern)
+                            .await
+                            .unwrap_or(cmd)
+                    }
+                    Err(e) => {
+                        error!("Failed to build RSSI breath: {e}");
+                        report_off = false;
+                        LedCmd::Off
+                    }
+                };
+            }
+            _ => match PatternProperties::new(&cmd) {
                 Ok(pattern) => {
-                    info!("Starting wave pattern");
-                    cmd = execute_pattern(&mut controller, &cmd_channel, pattern).await;
+                    info!("Starting {} pattern", cmd.name());
+                    if !cmd.is_transient() {
+                        persistent = Some(cmd);
+                    }
+                    cmd = match execute_pattern(&mut controller, &cmd_channel, &mut last, pattern)
+                        .await
+                    {
+                        Some(next) => next,
+                        None => {
+                            LED_IDLE.signal(());
+                            report_off = false;
+                            persistent.unwrap_or_else(|| IDLE_CMD.lock(Cell::get))
+                        }
+                    };
+                }
+                Err(e) => {
+                    error!("Received invalid {} command: {e}", cmd.name());
+                    report_off = false;
+                    cmd = persistent.unwrap_or(LedCmd::Off);
                 }
-                Err(e) => error!("Received invalid wave command: {e}"),
             },
-            LedCmd::Off => {
-                info!("Shutting led off");
-                cmd = execute_off(&mut controller, &cmd_channel).await
-            }
         }
     }
 }
@@ -0,0 +1,443 @@
+use core::f64::consts::PI;
+
+use embassy_time::Duration;
+use libm::{cos, fabs, trunc};
+use smart_leds::RGB;
+
+use crate::error::PatternError;
+use crate::led_cmd::{Fade, LED_COUNT, LedCmd, WaveShape, hsv_to_rgb, kelvin_to_rgb};
+
+/* Brightness tables of the led patterns: each pattern is played as a list of steps, each with its
+ * own brightness (and possibly color) and duration. Building them does not involve the hardware.
+ */
+pub const MAX_BRIGHTNESS_TABLE_LEN: usize = 70;
+const WAVE_TICK_PERIOD_MS: u64 = 30;
+/* Wave steps last at least the wave tick period */
+pub const MIN_WAVE_PERIOD_MS: u64 = MAX_BRIGHTNESS_TABLE_LEN as u64 * WAVE_TICK_PERIOD_MS;
+const MAX_BRIGHTNESS: u32 = 255;
+/* On time of strobe flashes, the off time filling the rest of the period */
+const STROBE_FLASH_MS: u64 = 20;
+/* Rainbow steps last at least the minimum tick period */
+const MIN_RAINBOW_PERIOD_MS: u64 = MAX_BRIGHTNESS_TABLE_LEN as u64 * MIN_TICK_PERIOD_MS;
+/* Minimum time spent waiting on each pattern step, so that even a pattern made of zero or near-zero
+ * durations never starves the other tasks of the executor
+ */
+pub const MIN_TICK_PERIOD_MS: u64 = 1;
+
+/// Brightness table entry as reported by debug commands: brightness and duration in ms
+#[cfg(feature = "debug-commands")]
+pub type TableEntry = (u8, u32);
+#[cfg(feature = "debug-commands")]
+pub type TableDump = heapless::Vec<TableEntry, MAX_BRIGHTNESS_TABLE_LEN>;
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct SubPatternProperties {
+    pub brightness: u8,
+    pub duration: Duration,
+    /// Step specific color, overriding the pattern one
+    pub color: Option<RGB<u8>>,
+    /// Only led lit during the step, all leds being lit if none
+    pub pixel: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct PatternProperties {
+    pub color: RGB<u8>,
+    pub duration: Duration,
+    pub brightness_table: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN],
+    pub brightness_table_len: usize,
+    /// Number of runs through the brightness table before switching the led off
+    pub repeat: Option<u16>,
+    pub fade: Fade,
+}
+
+fn compute_wave_table(
+    period: Duration,
+    shape: WaveShape,
+) -> Result<[SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN], PatternError> {
+    if period < Duration::from_millis(MIN_WAVE_PERIOD_MS) {
+        return Err(PatternError::WavePeriodTooShort {
+            min_ms: MIN_WAVE_PERIOD_MS,
+        });
+    }
+    let mut result: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] =
+        [Default::default(); MAX_BRIGHTNESS_TABLE_LEN];
+    /* Spread the period over all the steps, so that long waves get longer steps rather than a
+     * long hold at the end
+     */
+    let step = Duration::from_micros(period.as_micros() / MAX_BRIGHTNESS_TABLE_LEN as u64);
+
+    for (index, subpattern) in result.iter_mut().enumerate() {
+        let x = index as f64 / MAX_BRIGHTNESS_TABLE_LEN as f64;
+        let value: f64 = match shape {
+            WaveShape::Cosine => MAX_BRIGHTNESS as f64 / 2.0 * (1.0 + cos(PI * (2.0 * x - 1.0))),
+            WaveShape::Triangle => MAX_BRIGHTNESS as f64 * (1.0 - fabs(2.0 * x - 1.0)),
+            WaveShape::Sawtooth => MAX_BRIGHTNESS as f64 * x,
+        };
+        subpattern.brightness = trunc(value) as u8;
+        subpattern.duration = step;
+    }
+
+    /* Make sure that the last value is 0, and let it absorb the rounding so that the steps sum up
+     * exactly to the target period
+     */
+    result[MAX_BRIGHTNESS_TABLE_LEN - 1].brightness = 0;
+    result[MAX_BRIGHTNESS_TABLE_LEN - 1].duration =
+        period - step * (MAX_BRIGHTNESS_TABLE_LEN as u32 - 1);
+
+    Ok(result)
+}
+
+fn compute_rainbow_table(
+    period: Duration,
+    value: f32,
+) -> [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] {
+    let mut result: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] =
+        [Default::default(); MAX_BRIGHTNESS_TABLE_LEN];
+    let step = Duration::from_micros(period.as_micros() / MAX_BRIGHTNESS_TABLE_LEN as u64);
+
+    for (index, subpattern) in result.iter_mut().enumerate() {
+        let hue = 360.0 * index as f32 / MAX_BRIGHTNESS_TABLE_LEN as f32;
+        subpattern.brightness = MAX_BRIGHTNESS as u8;
+        subpattern.duration = step;
+        subpattern.color = Some(hsv_to_rgb(hue, 1.0, value));
+    }
+
+    /* Let the last step absorb the rounding, so that the steps sum up exactly to the period */
+    result[MAX_BRIGHTNESS_TABLE_LEN - 1].duration =
+        period - step * (MAX_BRIGHTNESS_TABLE_LEN as u32 - 1);
+
+    result
+}
+
+fn compute_chase_table(
+    period: Duration,
+) -> ([SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN], usize) {
+    let mut result: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] =
+        [Default::default(); MAX_BRIGHTNESS_TABLE_LEN];
+    /* There is nothing to chase along a single led, blink it instead */
+    if LED_COUNT == 1 {
+        result[0].brightness = MAX_BRIGHTNESS as u8;
+        result[0].duration = period / 2;
+        result[1].brightness = 0;
+        result[1].duration = period - result[0].duration;
+        return (result, 2);
+    }
+    let len = LED_COUNT.min(MAX_BRIGHTNESS_TABLE_LEN);
+    let step = Duration::from_micros(period.as_micros() / len as u64);
+    for (index, subpattern) in result[..len].iter_mut().enumerate() {
+        subpattern.brightness = MAX_BRIGHTNESS as u8;
+        subpattern.duration = step;
+        subpattern.pixel = Some(index);
+    }
+    /* Let the last step absorb the rounding, so that the steps sum up exactly to the period */
+    result[len - 1].duration = period - step * (len as u32 - 1);
+    (result, len)
+}
+
+impl PatternProperties {
+    pub fn new(value: &LedCmd) -> Result<Self, PatternError> {
+        match *value {
+            LedCmd::Solid {
+                color: c,
+                duration: d,
+                fade: f,
+            } => {
+                let mut table: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] =
+                    [Default::default(); MAX_BRIGHTNESS_TABLE_LEN];
+                /* The single step is refreshed regularly, to follow brightness and hue changes */
+                table[0].brightness = MAX_BRIGHTNESS as u8;
+                table[0].duration = Duration::from_millis(WAVE_TICK_PERIOD_MS);
+                Ok(PatternProperties {
+                    color: c,
+                    duration: d,
+                    brightness_table: table,
+                    brightness_table_len: 1,
+                    repeat: None,
+                    fade: f,
+                })
+            }
+            LedCmd::Blink {
+                color: c,
+                duration: d,
+                period: p,
+                duty_cycle: dc,
+                repeat: r,
+                fade: f,
+            } => {
+                if dc > 100 {
+                    return Err(PatternError::InvalidDutyCycle);
+                }
+                let mut table: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] =
+                    [Default::default(); MAX_BRIGHTNESS_TABLE_LEN];
+                /* Split the period with microsecond precision, and derive the off time from the
+                 * on time so that both always sum up exactly to the period
+                 */
+                table[0].brightness = MAX_BRIGHTNESS as u8;
+                table[0].duration = Duration::from_micros(p.as_micros() * u64::from(dc) / 100);
+                table[1].brightness = 0;
+                table[1].duration = p - table[0].duration;
+                /* Always on or always off blinks are a single step, rather than cycling through a
+                 * zero duration one
+                 */
+                let len = match dc {
+                    0 => {
+                        table[0] = table[1];
+                        1
+                    }
+                    100 => 1,
+                    _ => 2,
+                };
+                Ok(PatternProperties {
+                    color: c,
+                    duration: d,
+                    brightness_table: table,
+                    brightness_table_len: len,
+                    repeat: r,
+                    fade: f,
+                })
+            }
+            LedCmd::Wave {
+                color: c,
+                duration: d,
+                period: p,
+                duty_cycle: dc,
+                shape,
+                repeat: r,
+                fade: f,
+            } => {
+                if dc > 100 {
+                    return Err(PatternError::InvalidDutyCycle);
+                }
+                if p < Duration::from_millis(MIN_WAVE_PERIOD_MS) {
+                    return Err(PatternError::WavePeriodTooShort {
+                        min_ms: MIN_WAVE_PERIOD_MS,
+                    });
+                }
+                let table = compute_wave_table(p, shape)?;
+                Ok(PatternProperties {
+                    color: c,
+                    duration: d,
+                    brightness_table: table,
+                    brightness_table_len: MAX_BRIGHTNESS_TABLE_LEN,
+                    repeat: r,
+                    fade: f,
+                })
+            }
+            LedCmd::Strobe {
+                color: c,
+                duration: d,
+                period: p,
+                repeat: r,
+                fade: f,
+            } => {
+                let flash = Duration::from_millis(STROBE_FLASH_MS);
+                if p < flash {
+                    return Err(PatternError::PeriodTooShort {
+                        min_ms: STROBE_FLASH_MS,
+                    });
+                }
+                let mut table: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] =
+                    [Default::default(); MAX_BRIGHTNESS_TABLE_LEN];
+                table[0].brightness = MAX_BRIGHTNESS as u8;
+                table[0].duration = flash;
+                table[1].brightness = 0;
+                table[1].duration = p - flash;
+                Ok(PatternProperties {
+                    color: c,
+                    duration: d,
+                    brightness_table: table,
+                    brightness_table_len: 2,
+                    repeat: r,
+                    fade: f,
+                })
+            }
+            LedCmd::Chase {
+                color: c,
+                duration: d,
+                period: p,
+                repeat: r,
+                fade: f,
+            } => {
+                let (table, len) = compute_chase_table(p);
+                Ok(PatternProperties {
+                    color: c,
+                    duration: d,
+                    brightness_table: table,
+                    brightness_table_len: len,
+                    repeat: r,
+                    fade: f,
+                })
+            }
+            LedCmd::Sequence {
+                colors,
+                duration: d,
+                dwell,
+                fade: f,
+            } => {
+                let mut table: [SubPatternProperties; MAX_BRIGHTNESS_TABLE_LEN] =
+                    [Default::default(); MAX_BRIGHTNESS_TABLE_LEN];
+                let mut len = 0;
+                for (subpattern, color) in table.iter_mut().zip(colors.into_iter().flatten()) {
+                    subpattern.brightness = MAX_BRIGHTNESS as u8;
+                    subpattern.duration = dwell;
+                    subpattern.color = Some(color);
+                    len += 1;
+                }
+                if len == 0 {
+                    return Err(PatternError::EmptySequence);
+                }
+                Ok(PatternProperties {
+                    color: table[0].color.unwrap_or_default(),
+                    duration: d,
+                    brightness_table: table,
+                    brightness_table_len: len,
+                    repeat: None,
+                    fade: f,
+                })
+            }
+            LedCmd::Rainbow {
+                duration: d,
+                period: p,
+                brightness: b,
+                fade: f,
+            } => {
+                if p < Duration::from_millis(MIN_RAINBOW_PERIOD_MS) {
+                    return Err(PatternError::PeriodTooShort {
+                        min_ms: MIN_RAINBOW_PERIOD_MS,
+                    });
+                }
+                let table = compute_rainbow_table(p, f32::from(b) / f32::from(u8::MAX));
+                Ok(PatternProperties {
+                    color: table[0].color.unwrap_or_default(),
+                    duration: d,
+                    brightness_table: table,
+                    brightness_table_len: MAX_BRIGHTNESS_TABLE_LEN,
+                    repeat: None,
+                    fade: f,
+                })
+            }
+            LedCmd::White {
+                kelvin,
+                duration: d,
+                brightness: b,
+                fade: f,
+            } => PatternProperties::new(
+                &LedCmd::Solid {
+                    color: kelvin_to_rgb(kelvin),
+                    duration: d,
+                    fade: f,
+                }
+                .scaled(b),
+            ),
+            _ => Err(PatternError::UnsupportedCommand),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blink(period_ms: u64, duty_cycle: u8) -> PatternProperties {
+        PatternProperties::new(&LedCmd::Blink {
+            color: RGB::new(u8::MAX, 0, 0),
+            duration: Duration::from_ticks(0),
+            period: Duration::from_millis(period_ms),
+            duty_cycle,
+            repeat: None,
+            fade: Fade::NONE,
+        })
+        .expect("valid blink")
+    }
+
+    #[test]
+    fn blink_steps_sum_up_to_the_period() {
+        for period_ms in [1, 7, 50, 333, 1000, 12_345] {
+            for duty_cycle in 1..100 {
+                let pattern = blink(period_ms, duty_cycle);
+                let table = &pattern.brightness_table;
+                assert_eq!(pattern.brightness_table_len, 2);
+                assert_eq!(
+                    table[0].duration + table[1].duration,
+                    Duration::from_millis(period_ms),
+                    "period {period_ms}ms, duty cycle {duty_cycle}%"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn full_duty_cycle_blink_stays_on() {
+        let pattern = blink(1000, 100);
+        assert_eq!(pattern.brightness_table_len, 1);
+        assert_eq!(pattern.brightness_table[0].brightness, MAX_BRIGHTNESS as u8);
+        assert_eq!(
+            pattern.brightness_table[0].duration,
+            Duration::from_millis(1000)
+        );
+    }
+
+    #[test]
+    fn null_duty_cycle_blink_stays_off() {
+        let pattern = blink(1000, 0);
+        assert_eq!(pattern.brightness_table_len, 1);
+        assert_eq!(pattern.brightness_table[0].brightness, 0);
+        assert_eq!(
+            pattern.brightness_table[0].duration,
+            Duration::from_millis(1000)
+        );
+    }
+
+    #[test]
+    fn half_duty_cycle_blink_splits_the_period_evenly() {
+        let pattern = blink(1000, 50);
+        let table = &pattern.brightness_table;
+        assert_eq!(pattern.brightness_table_len, 2);
+        assert_eq!(
+            (table[0].brightness, table[1].brightness),
+            (MAX_BRIGHTNESS as u8, 0)
+        );
+        assert_eq!(table[0].duration, Duration::from_millis(500));
+        assert_eq!(table[1].duration, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn wave_table_at_the_minimum_period() {
+        let period = Duration::from_millis(MIN_WAVE_PERIOD_MS);
+        let table = compute_wave_table(period, WaveShape::Cosine).expect("valid period");
+        let total = table
+            .iter()
+            .fold(Duration::from_ticks(0), |acc, step| acc + step.duration);
+        assert_eq!(total, period);
+        assert_eq!(table[MAX_BRIGHTNESS_TABLE_LEN - 1].brightness, 0);
+        assert_eq!(
+            table[MAX_BRIGHTNESS_TABLE_LEN - 1].duration,
+            Duration::from_millis(WAVE_TICK_PERIOD_MS)
+        );
+    }
+
+    #[test]
+    fn wave_table_below_the_minimum_period() {
+        let period = Duration::from_millis(MIN_WAVE_PERIOD_MS) - Duration::from_micros(1);
+        assert_eq!(
+            compute_wave_table(period, WaveShape::Cosine).err(),
+            Some(PatternError::WavePeriodTooShort {
+                min_ms: MIN_WAVE_PERIOD_MS
+            })
+        );
+    }
+
+    #[test]
+    fn blink_split_is_not_quantized_to_milliseconds() {
+        let pattern = blink(50, 33);
+        assert_eq!(
+            pattern.brightness_table[0].duration,
+            Duration::from_micros(16_500)
+        );
+        assert_eq!(
+            pattern.brightness_table[1].duration,
+            Duration::from_micros(33_500)
+        );
+    }
+}
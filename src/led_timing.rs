@@ -0,0 +1,130 @@
+use log::{info, warn};
+
+/// Frequency the RMT controller must be configured with: esp-hal-smartled converts its pulse
+/// durations to RMT ticks assuming this source clock, with no clock divider
+pub const RMT_CLOCK_MHZ: u32 = 80;
+
+/* Pulse durations used by esp-hal-smartled for each bit, in ns. They are fixed by the driver, so
+ * the timing profiles below only describe what a given strip accepts, and allow to check at
+ * startup that the driver output fits in it.
+ */
+const DRIVER_T0H_NS: u32 = 400;
+const DRIVER_T0L_NS: u32 = 850;
+const DRIVER_T1H_NS: u32 = 850;
+const DRIVER_T1L_NS: u32 = 400;
+/* Patterns never refresh the strip more often than every millisecond, which is way above any
+ * reset (latch) duration required by the supported strips
+ */
+const MIN_FRAME_INTERVAL_US: u32 = 1000;
+
+/// Accepted range for a pulse duration, in ns
+#[derive(Clone, Copy, Debug)]
+struct PulseWindow {
+    min_ns: u32,
+    max_ns: u32,
+}
+
+impl PulseWindow {
+    const fn new(min_ns: u32, max_ns: u32) -> Self {
+        PulseWindow { min_ns, max_ns }
+    }
+
+    fn contains(&self, ns: u32) -> bool {
+        (self.min_ns..=self.max_ns).contains(&ns)
+    }
+}
+
+/// Timing constraints of a led strip model, as found in its datasheet
+#[derive(Clone, Copy, Debug)]
+pub struct TimingProfile {
+    name: &'static str,
+    t0h: PulseWindow,
+    t0l: PulseWindow,
+    t1h: PulseWindow,
+    t1l: PulseWindow,
+    reset_us: u32,
+}
+
+const PROFILES: [TimingProfile; 3] = [
+    TimingProfile {
+        name: "ws2812",
+        t0h: PulseWindow::new(250, 550),
+        t0l: PulseWindow::new(700, 1000),
+        t1h: PulseWindow::new(650, 950),
+        t1l: PulseWindow::new(300, 600),
+        reset_us: 50,
+    },
+    TimingProfile {
+        name: "ws2812b",
+        t0h: PulseWindow::new(220, 380),
+        t0l: PulseWindow::new(580, 1600),
+        t1h: PulseWindow::new(580, 1600),
+        t1l: PulseWindow::new(220, 420),
+        reset_us: 280,
+    },
+    TimingProfile {
+        name: "sk6812",
+        t0h: PulseWindow::new(150, 450),
+        t0l: PulseWindow::new(750, 1050),
+        t1h: PulseWindow::new(450, 750),
+        t1l: PulseWindow::new(450, 750),
+        reset_us: 80,
+    },
+];
+
+/// Timing profile of the mounted strip, selected at build time with the `NBC_LED_TIMING`
+/// environment variable. The WS2812 profile is used when it is not set or unknown.
+pub fn selected_profile() -> TimingProfile {
+    let name = option_env!("NBC_LED_TIMING").unwrap_or(PROFILES[0].name);
+    PROFILES
+        .iter()
+        .find(|p| p.name.eq_ignore_ascii_case(name))
+        .copied()
+        .unwrap_or_else(|| {
+            warn!(
+                "Unknown led timing profile {name}, using {}",
+                PROFILES[0].name
+            );
+            PROFILES[0]
+        })
+}
+
+/// Actual pulse duration once quantized to RMT ticks
+fn effective_ns(ns: u32) -> u32 {
+    let ticks = ns * RMT_CLOCK_MHZ / 1000;
+    ticks * 1000 / RMT_CLOCK_MHZ
+}
+
+/// Log the effective pulse timings generated for the led strip, and warn about any of them not
+/// matching the selected timing profile
+pub fn check_timing(profile: &TimingProfile) {
+    let pulses = [
+        ("T0H", DRIVER_T0H_NS, profile.t0h),
+        ("T0L", DRIVER_T0L_NS, profile.t0l),
+        ("T1H", DRIVER_T1H_NS, profile.t1h),
+        ("T1L", DRIVER_T1L_NS, profile.t1l),
+    ];
+    info!(
+        "Led timing ({} profile, RMT clock {RMT_CLOCK_MHZ}MHz): T0H {}ns, T0L {}ns, T1H {}ns, T1L {}ns, reset >= {MIN_FRAME_INTERVAL_US}us",
+        profile.name,
+        effective_ns(DRIVER_T0H_NS),
+        effective_ns(DRIVER_T0L_NS),
+        effective_ns(DRIVER_T1H_NS),
+        effective_ns(DRIVER_T1L_NS),
+    );
+    for (name, ns, window) in pulses {
+        let ns = effective_ns(ns);
+        if !window.contains(ns) {
+            warn!(
+                "Led {name} pulse ({ns}ns) out of the {} range ({}-{}ns), colors may glitch",
+                profile.name, window.min_ns, window.max_ns
+            );
+        }
+    }
+    if profile.reset_us > MIN_FRAME_INTERVAL_US {
+        warn!(
+            "Led reset time ({}us) longer than the minimum frame interval",
+            profile.reset_us
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::config::LogCategories;
+
+/* Log filter directives, following the same syntax as the one accepted by esp-println: a comma
+ * separated list of `level`, `target` or `target=level` entries
+ */
+const LOG_FILTER: &str = match option_env!("ESP_LOG") {
+    Some(filter) => filter,
+    None => "info",
+};
+
+static NETWORK_ENABLED: AtomicBool = AtomicBool::new(true);
+static WS_ENABLED: AtomicBool = AtomicBool::new(true);
+static LED_ENABLED: AtomicBool = AtomicBool::new(true);
+static BUTTON_ENABLED: AtomicBool = AtomicBool::new(true);
+
+const CATEGORIES: [(&str, &AtomicBool); 4] = [
+    ("neon_beat_buzzer::network", &NETWORK_ENABLED),
+    ("neon_beat_buzzer::websocket", &WS_ENABLED),
+    ("neon_beat_buzzer::led", &LED_ENABLED),
+    ("neon_beat_buzzer::button", &BUTTON_ENABLED),
+];
+
+pub fn set_categories(categories: &LogCategories) {
+    NETWORK_ENABLED.store(categories.network, Ordering::Relaxed);
+    WS_ENABLED.store(categories.ws, Ordering::Relaxed);
+    LED_ENABLED.store(categories.led, Ordering::Relaxed);
+    BUTTON_ENABLED.store(categories.button, Ordering::Relaxed);
+}
+
+fn parse_directive(directive: &str) -> (&str, LevelFilter) {
+    match directive.trim().split_once('=') {
+        Some((target, level)) => (
+            target,
+            LevelFilter::from_str(level).unwrap_or(LevelFilter::Trace),
+        ),
+        None => match LevelFilter::from_str(directive.trim()) {
+            Ok(level) => ("", level),
+            Err(_) => (directive.trim(), LevelFilter::Trace),
+        },
+    }
+}
+
+/// Find the level filter applying to a target, the most specific directive winning
+fn level_for(target: &str) -> LevelFilter {
+    let mut level = LevelFilter::Off;
+    let mut matched_len = 0;
+    for (directive_target, directive_level) in LOG_FILTER.split(',').map(parse_directive) {
+        if target.starts_with(directive_target) && directive_target.len() >= matched_len {
+            matched_len = directive_target.len();
+            level = directive_level;
+        }
+    }
+    level
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        let category_enabled = CATEGORIES
+            .iter()
+            .find(|(prefix, _)| target.starts_with(prefix))
+            .is_none_or(|(_, enabled)| enabled.load(Ordering::Relaxed));
+        category_enabled && metadata.level() <= level_for(target)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            esp_println::println!("{} - {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+pub fn init() {
+    let max_level = LOG_FILTER
+        .split(',')
+        .map(|directive| parse_directive(directive).1)
+        .max()
+        .unwrap_or(LevelFilter::Off);
+    // SAFETY: called once at boot, before any other task is running
+    unsafe {
+        log::set_logger_racy(&LOGGER).ok();
+        log::set_max_level_racy(max_level);
+    }
+}
@@ -6,35 +6,301 @@
     holding buffers for the duration of a data transfer."
 )]
 
+mod backoff;
+#[cfg(feature = "battery")]
+mod battery;
 mod button;
+mod buzzer;
+mod buzzer_cmd;
+mod command;
+mod config;
 mod error;
+mod framing;
+#[cfg(feature = "latency-stats")]
+mod latency;
 mod led_cmd;
 mod led_driver;
+mod led_pattern;
+mod led_timing;
+mod logger;
+mod mdns;
 mod network;
+mod persistent;
+mod preset;
+mod state;
+mod status;
+mod storage;
+mod thermal;
+mod watchdog;
 mod websocket;
+mod wifi;
 
 use embassy_executor::Spawner;
-use embassy_futures::select::{Either, select};
+use embassy_futures::select::{Either4, select4};
 use embassy_net::StackResources;
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
-use embassy_time::Duration;
-use esp_hal::{clock::CpuClock, rmt::Rmt, rng::Rng, time::Rate, timer::timg::TimerGroup};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{
+    clock::CpuClock,
+    rmt::Rmt,
+    rng::Rng,
+    rtc_cntl::{
+        Rtc, SocResetReason, reset_reason,
+        sleep::{RtcioWakeupSource, WakeupLevel},
+    },
+    system::Cpu,
+    time::Rate,
+    timer::timg::TimerGroup,
+};
 use esp_radio::wifi::ControllerConfig;
-use log::info;
+use log::{debug, error, info, warn};
 use smart_leds::RGB;
 use static_cell::StaticCell;
 
 use crate::{
-    button::button_task,
+    button::{Button, ButtonEvent},
+    buzzer::Buzzer,
+    buzzer_cmd::BuzzerCmd,
+    command::Command,
+    config::{Config, LedEvent, OfflineBuzzPolicy},
+    led_cmd::{Fade, LedCmd, StoredLedCmd, WaveShape},
     led_driver::Led,
-    network::{connection, net_task},
+    mdns::mdns_task,
+    network::{
+        build_networks, connection, ip_config, net_task, request_reconnect, rssi, set_networks,
+        set_wifi_settings,
+    },
+    preset::{PresetSlot, Presets},
+    state::{ConnectionState, connection_state},
+    status::DeviceStatus,
+    storage::Storage,
+    thermal::Thermometer,
+    watchdog::{Subsystem, watchdog_task},
     websocket::{Websocket, WebsocketEvent},
+    wifi::check_networks,
+};
+
+const PANIC_MAGIC: u32 = 0x5041_4e43;
+/* A buzzer crashing again right after each boot stops rebooting after this many crashes in a row,
+ * and sleeps until the button is pushed rather than draining its battery and flooding NBC with
+ * connections
+ */
+const MAX_CONSECUTIVE_CRASHES: u32 = 5;
+/* Button pushes happening right after boot or connection are ignored, as they are likely due to
+ * startup transients (floating input, button held while handling the buzzer...)
+ */
+const STARTUP_GRACE_PERIOD_MS: u64 = 200;
+/* Two buzzes closer than this can not come from a human, and are rather due to electrical noise */
+const MIN_INTER_BUZZ_MS: u64 = 30;
+
+/* When enabled by the configuration, buzzes sent while connected must be acknowledged by the
+ * host within this delay, or they are sent again. Once all retransmissions are exhausted, the buzz
+ * is considered lost and the player is told with the led.
+ */
+const BUZZ_ACK_TIMEOUT_MS: u64 = 500;
+const BUZZ_MAX_RETRANSMISSIONS: u8 = 2;
+/* The signal strength is checked periodically, and only reported to NBC when it changed enough, to
+ * limit the traffic
+ */
+const RSSI_REPORT_PERIOD_MS: u64 = 5000;
+const RSSI_REPORT_THRESHOLD_DB: u8 = 3;
+/* Without any button push nor host command for this long, the buzzer goes to deep sleep until the
+ * button is pushed again, waking up as if it had just been powered on
+ */
+const SLEEP_TIMEOUT_SECS: u64 = 30 * 60;
+const SLEEP_LED_OFF_DELAY_MS: u64 = 300;
+/* The last pattern from the host is only saved to flash once it has been kept this long, since
+ * hosts may change patterns many times per second and each save wears the flash and stalls the
+ * executor while erasing
+ */
+const LED_SAVE_DELAY_SECS: u64 = 10;
+/* The battery charge is reported less often, as it changes slowly */
+#[cfg(feature = "battery")]
+const BATTERY_REPORT_PERIOD_SECS: u64 = 60;
+#[cfg(feature = "battery")]
+const LOW_BATTERY_PERCENT: u8 = 15;
+
+/* Quick red flashes telling the player that the buzz has not been sent */
+const BUZZ_DROPPED_LED: LedCmd = LedCmd::Blink {
+    color: RGB {
+        r: u8::MAX,
+        g: 0,
+        b: 0,
+    },
+    duration: Duration::from_millis(600),
+    period: Duration::from_millis(200),
+    duty_cycle: 50,
+    repeat: None,
+    fade: Fade::NONE,
+};
+
+/* Fast orange flashes telling that the buzzer can not connect because of its wifi configuration */
+const INVALID_WIFI_CONFIG_LED: LedCmd = LedCmd::Blink {
+    color: RGB {
+        r: u8::MAX,
+        g: 0x40,
+        b: 0,
+    },
+    duration: Duration::from_secs(0),
+    period: Duration::from_millis(300),
+    duty_cycle: 50,
+    repeat: None,
+    fade: Fade::NONE,
+};
+
+/* Orange flashes telling that the access point was joined, but did not provide any IP address */
+const ADDRESS_TIMEOUT_LED: LedCmd = LedCmd::Blink {
+    color: RGB {
+        r: u8::MAX,
+        g: 0x40,
+        b: 0,
+    },
+    duration: Duration::from_millis(1500),
+    period: Duration::from_millis(150),
+    duty_cycle: 50,
+    repeat: None,
+    fade: Fade::NONE,
+};
+
+/* Slow red flashes telling that the battery needs charging, shown periodically over any pattern */
+#[cfg(feature = "battery")]
+const LOW_BATTERY_LED: LedCmd = LedCmd::Blink {
+    color: RGB {
+        r: u8::MAX,
+        g: 0,
+        b: 0,
+    },
+    duration: Duration::from_secs(4),
+    period: Duration::from_secs(2),
+    duty_cycle: 50,
+    repeat: None,
+    fade: Fade::NONE,
 };
 
+/* Slow blue breathing telling that the buzzer is alive, but still looking for NBC */
+const CONNECTING_LED: LedCmd = LedCmd::Wave {
+    color: RGB {
+        r: 0,
+        g: 0,
+        b: u8::MAX,
+    },
+    duration: Duration::from_secs(0),
+    period: Duration::from_secs(4),
+    duty_cycle: 50,
+    shape: WaveShape::Cosine,
+    repeat: None,
+    fade: Fade::NONE,
+};
+
+/* Dim green telling that the buzzer is connected to NBC, until the host sets its own led */
+const CONNECTED_LED: LedCmd = LedCmd::Solid {
+    color: RGB {
+        r: 0,
+        g: 0x40,
+        b: 0,
+    },
+    duration: Duration::from_secs(0),
+    fade: Fade::NONE,
+};
+
+/* Dim amber telling that the buzzer just connected to NBC and still ignores button pushes, until
+ * the grace period is over
+ */
+const GRACE_LED: LedCmd = LedCmd::Solid {
+    color: RGB {
+        r: 0x40,
+        g: 0x20,
+        b: 0,
+    },
+    duration: Duration::from_secs(0),
+    fade: Fade::NONE,
+};
+
+/* Red flashes telling that the connection to NBC has just been lost, before connecting again */
+const DISCONNECTED_LED: LedCmd = LedCmd::Blink {
+    color: RGB {
+        r: u8::MAX,
+        g: 0,
+        b: 0,
+    },
+    duration: Duration::from_millis(1500),
+    period: Duration::from_millis(500),
+    duty_cycle: 50,
+    repeat: None,
+    fade: Fade::NONE,
+};
+
+/* Short beep acknowledging a button push */
+const BUZZ_TONE: BuzzerCmd = BuzzerCmd::Tone {
+    frequency_hz: 2000,
+    duration: Duration::from_millis(80),
+};
+
+/* Survives a software reset, so that the next boot can tell that it follows a panic */
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PANIC_MARKER: u32 = 0;
+/* Number of boots in a row following a crash, also surviving software and watchdog resets */
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut CRASH_STREAK: u32 = 0;
+
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     info!("{}", info);
-    loop {}
+    // SAFETY: single core, and nothing else runs once we are panicking
+    unsafe { (&raw mut PANIC_MARKER).write_volatile(PANIC_MAGIC) };
+    esp_hal::system::software_reset()
+}
+
+/// Enter deep sleep, with the button as the only wake up source
+fn deep_sleep(rtc: &mut Rtc<'_>) -> ! {
+    // SAFETY: the button task owning the pin never runs again, as deep sleep ends with a reset
+    let mut button_pin = unsafe { esp_hal::peripherals::GPIO2::steal() };
+    let mut wake_pins: [(&mut dyn esp_hal::gpio::RtcPinWithResistors, WakeupLevel); 1] =
+        [(&mut button_pin, WakeupLevel::Low)];
+    let wake_source = RtcioWakeupSource::new(&mut wake_pins);
+    rtc.sleep_deep(&[&wake_source])
+}
+
+/// Check whether the current boot follows a crash (panic or watchdog reset), and clear the panic
+/// marker for the next boot
+fn boot_follows_crash() -> bool {
+    // SAFETY: only accessed from the main task at boot and from the panic handler
+    let panicked = unsafe {
+        let panicked = (&raw const PANIC_MARKER).read_volatile() == PANIC_MAGIC;
+        (&raw mut PANIC_MARKER).write_volatile(0);
+        panicked
+    };
+    let reason = reset_reason(Cpu::ProCpu);
+    info!("Reset reason: {reason:?}");
+    let watchdog = matches!(
+        reason,
+        Some(
+            SocResetReason::CoreMwdt0
+                | SocResetReason::CoreMwdt1
+                | SocResetReason::CoreRtcWdt
+                | SocResetReason::Cpu0Mwdt0
+                | SocResetReason::Cpu0RtcWdt
+                | SocResetReason::SysRtcWdt
+                | SocResetReason::SysSuperWdt
+        )
+    );
+    /* The rtc memory content is undefined after a power-on reset */
+    (panicked && reason != Some(SocResetReason::ChipPowerOn)) || watchdog
+}
+
+/// Count the boots in a row following a crash, including the current one
+fn crash_streak(crashed: bool) -> u32 {
+    // SAFETY: only accessed from the main task at boot
+    unsafe {
+        /* Cleared on every other boot, which covers the undefined content after power-on */
+        let streak = if crashed {
+            (&raw const CRASH_STREAK).read_volatile().saturating_add(1)
+        } else {
+            0
+        };
+        (&raw mut CRASH_STREAK).write_volatile(streak);
+        streak
+    }
 }
 
 extern crate alloc;
@@ -42,12 +308,75 @@ extern crate alloc;
 esp_bootloader_esp_idf::esp_app_desc!();
 
 static WS_CHANNEL: StaticCell<Channel<NoopRawMutex, WebsocketEvent, 3>> = StaticCell::new();
-static BUTTON_CHANNEL: StaticCell<Channel<NoopRawMutex, bool, 1>> = StaticCell::new();
 static RESOURCES_CELL: StaticCell<StackResources<3>> = StaticCell::new();
 
+/// Buzz waiting for the host acknowledgement
+#[derive(Clone, Copy)]
+struct PendingBuzz {
+    seq: u32,
+    simulated: bool,
+    pressed_at: Instant,
+    deadline: Instant,
+    retransmissions: u8,
+}
+
+/// Handle a button push, returning the buzz to be acknowledged by the host, if any
+async fn buzz(
+    led: &mut Led,
+    ws: &mut Websocket,
+    presets: &Presets,
+    config: &Config,
+    simulated: bool,
+    pressed_at: Instant,
+) -> Option<PendingBuzz> {
+    if connection_state() != ConnectionState::WsConnected
+        && config.offline_buzz == OfflineBuzzPolicy::Drop
+    {
+        warn!("Dropping buzz, buzzer is not connected");
+        led.set(config.event_led(LedEvent::Alert, BUZZ_DROPPED_LED))
+            .await;
+        return None;
+    }
+    if let Some(cmd) = presets.get(PresetSlot::OnBuzz) {
+        led.set(config.event_led(LedEvent::Buzz, cmd)).await;
+    }
+    match ws.send_button_pushed(config.tiebreak, simulated, pressed_at) {
+        /* Buzzes queued while offline are only sent on reconnection, acknowledgements are not
+         * awaited for them
+         */
+        Ok(seq) if config.buzz_ack && connection_state() == ConnectionState::WsConnected => {
+            Some(PendingBuzz {
+                seq,
+                simulated,
+                pressed_at,
+                deadline: Instant::now() + Duration::from_millis(BUZZ_ACK_TIMEOUT_MS),
+                retransmissions: 0,
+            })
+        }
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Dropping buzz: {e}");
+            led.set(config.event_led(LedEvent::Alert, BUZZ_DROPPED_LED))
+                .await;
+            None
+        }
+    }
+}
+
+/// Apply a new configuration to the running buzzer
+async fn apply_config(led: &mut Led, presets: &Presets, config: &Config) {
+    led.set_brightness(config.brightness);
+    led.set_enabled(config.led_enabled).await;
+    set_wifi_settings(config.wifi_settings());
+    logger::set_categories(&config.log);
+    if let Some(idle) = presets.get(PresetSlot::Idle) {
+        led.set_idle(config.event_led(LedEvent::Idle, idle));
+    }
+}
+
 #[esp_rtos::main]
 async fn main(spawner: Spawner) -> ! {
-    esp_println::logger::init_logger_from_env();
+    logger::init();
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
 
@@ -57,57 +386,494 @@ async fn main(spawner: Spawner) -> ! {
     let sw_interrupt =
         esp_hal::interrupt::software::SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
     esp_rtos::start(timg0.timer0, sw_interrupt.software_interrupt0);
+    let mut rtc = Rtc::new(peripherals.LPWR);
+    let timg1 = TimerGroup::new(peripherals.TIMG1);
+    spawner.spawn(watchdog_task(timg1.wdt).expect("Failed to spawn watchdog task"));
+
+    let mut storage = Storage::new(peripherals.FLASH);
+    let mut persistent = storage.load();
+    persistent.boot_count = persistent.boot_count.wrapping_add(1);
+    let crashed = boot_follows_crash();
+    if crashed {
+        persistent.crash_count = persistent.crash_count.wrapping_add(1);
+    }
+    storage.save(&persistent);
+    let streak = crash_streak(crashed);
+    if streak >= MAX_CONSECUTIVE_CRASHES {
+        error!("{streak} crashes in a row, sleeping until the button is pushed");
+        deep_sleep(&mut rtc);
+    }
+    logger::set_categories(&persistent.config.log);
+    set_wifi_settings(persistent.config.wifi_settings());
+    info!(
+        "Boot count: {}, crash count: {}",
+        persistent.boot_count, persistent.crash_count
+    );
 
     let resources = RESOURCES_CELL.init(StackResources::<3>::new());
-    let button_channel: &'static mut _ = BUTTON_CHANNEL.init(Channel::new());
     let ws_channel: &'static mut _ = WS_CHANNEL.init(Channel::new());
 
     let (wifi_controller, wifi_interface) =
         esp_radio::wifi::new(peripherals.WIFI, ControllerConfig::default())
             .expect("Failed to initialize Wi-Fi controller");
     info!("Buzzer initialized");
-    let config = embassy_net::Config::dhcpv4(Default::default());
+    let config = ip_config().unwrap_or_else(|e| {
+        error!("{e}, falling back to DHCP");
+        embassy_net::Config::dhcpv4(Default::default())
+    });
     let rng = Rng::new();
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
     let (stack, runner) = embassy_net::new(wifi_interface.station, config, resources, seed);
-    let rmt =
-        Rmt::new(peripherals.RMT, Rate::from_mhz(80)).expect("Failed to initialize RMT controller");
+    let rmt = Rmt::new(peripherals.RMT, Rate::from_mhz(led_timing::RMT_CLOCK_MHZ))
+        .expect("Failed to initialize RMT controller");
     let mut led = Led::new(&spawner, rmt.into_async(), peripherals.GPIO3);
+    led.set_brightness(persistent.config.brightness);
+    led.set_enabled(persistent.config.led_enabled).await;
+    /* Pattern received last from the host, shown once idle until a new one is received */
+    let mut last_led = match persistent.last_led.as_ref().map(LedCmd::try_from) {
+        Some(Ok(cmd)) => {
+            info!("Restoring last {} pattern", cmd.name());
+            cmd
+        }
+        Some(Err(e)) => {
+            warn!("Failed to restore last led pattern: {e}");
+            LedCmd::Off
+        }
+        None => LedCmd::Off,
+    };
+    led.set_idle(last_led);
+    /* Credentials provisioned by the host take precedence over the build-time ones */
+    let wifi_check = if persistent.wifi.is_empty() {
+        build_networks()
+    } else {
+        check_networks(&persistent.wifi).map(|()| persistent.wifi.clone())
+    };
+    match &wifi_check {
+        Ok(networks) => set_networks(networks.clone()),
+        Err(e) => error!("Invalid wifi configuration, not connecting: {e}"),
+    }
     spawner.spawn(connection(wifi_controller).expect("Failed to spawn wifi task"));
     spawner.spawn(net_task(runner).expect("Failed to spawn network task"));
-    spawner.spawn(
-        button_task(peripherals.GPIO2.into(), button_channel.sender())
-            .expect("Failed to spawn button task"),
-    );
+    spawner.spawn(mdns_task(stack, stack.hardware_address()).expect("Failed to spawn mDNS task"));
+    let mut button = Button::new(&spawner, peripherals.IO_MUX, peripherals.GPIO2.into());
+    let mut thermometer = Thermometer::new(peripherals.TSENS);
+    let mut buzzer = Buzzer::new(&spawner, peripherals.LEDC, peripherals.GPIO4.into());
+    #[cfg(feature = "battery")]
+    let battery = battery::Battery::new(&spawner, peripherals.ADC1, peripherals.GPIO0);
 
     let mut ws = Websocket::new(&spawner, stack, ws_channel.sender());
 
-    let connecting_blink = led_cmd::LedCmd::Blink {
-        color: RGB {
-            r: u8::MAX,
-            g: u8::MAX,
-            b: u8::MAX,
-        },
-        duration: Duration::from_secs(0),
-        period: Duration::from_secs(5),
-        duty_cycle: 2,
-    };
-    led.set(connecting_blink).await;
+    if wifi_check.is_ok() {
+        led.set(persistent.config.event_led(LedEvent::Idle, CONNECTING_LED))
+            .await;
+    } else {
+        led.set(
+            persistent
+                .config
+                .event_led(LedEvent::Alert, INVALID_WIFI_CONFIG_LED),
+        )
+        .await;
+    }
+    let mut presets = Presets::default();
+    let mut grace_until = Instant::now() + Duration::from_millis(STARTUP_GRACE_PERIOD_MS);
+    let mut grace_led = false;
+    let mut last_buzz: Option<Instant> = None;
+    let mut unacked: Option<PendingBuzz> = None;
+    let mut rejected_buzzes: u32 = 0;
+    let mut next_rssi_check = Instant::now() + Duration::from_millis(RSSI_REPORT_PERIOD_MS);
+    let mut reported_rssi: Option<i8> = None;
+    let mut last_activity = Instant::now();
+    let mut sleep_enabled = true;
+    /* Time at which the last host pattern, not saved yet, is saved to flash */
+    let mut led_save_at: Option<Instant> = None;
+    #[cfg(feature = "battery")]
+    let mut next_battery_report = Instant::now();
+    #[cfg(feature = "latency-stats")]
+    let mut buzz_latency = latency::LatencyStats::new("Buzz");
     loop {
-        match select(ws_channel.receive(), button_channel.receive()).await {
-            Either::First(WebsocketEvent::Connected) => {
+        watchdog::feed(Subsystem::Main);
+        /* Next end of the grace period, buzz acknowledgement timeout, RSSI check, sleep or pattern
+         * save
+         */
+        let deadline = [
+            grace_led.then_some(grace_until),
+            unacked.map(|pending| pending.deadline),
+            Some(next_rssi_check),
+            sleep_enabled.then(|| last_activity + Duration::from_secs(SLEEP_TIMEOUT_SECS)),
+            led_save_at,
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let timeout = async move {
+            match deadline {
+                Some(deadline) => Timer::at(deadline).await,
+                None => core::future::pending().await,
+            }
+        };
+        let event = select4(
+            ws_channel.receive(),
+            button.wait_for_event(),
+            timeout,
+            led.wait_for_idle(),
+        )
+        .await;
+        if matches!(
+            event,
+            Either4::First(WebsocketEvent::Command(_)) | Either4::Second(_)
+        ) {
+            last_activity = Instant::now();
+        }
+        match event {
+            Either4::Fourth(_) => {
+                if connection_state() == ConnectionState::WsConnected
+                    && let Err(e) = ws.send_led_idle()
+                {
+                    warn!("Dropping led idle notification: {e}");
+                }
+            }
+            Either4::First(WebsocketEvent::Connected) => {
                 info!("Buzzer is now connected to NBC");
-                ws.send_identify().await;
+                if let Err(e) = ws.send_identify(&persistent.config.name) {
+                    warn!("Dropping identification: {e}");
+                }
+                grace_until = Instant::now() + Duration::from_millis(STARTUP_GRACE_PERIOD_MS);
+                grace_led = true;
+                reported_rssi = None;
+                led.set(persistent.config.event_led(LedEvent::Idle, GRACE_LED))
+                    .await;
+            }
+            Either4::Third(_) => {
+                let now = Instant::now();
+                if led_save_at.is_some_and(|at| now >= at) {
+                    led_save_at = None;
+                    storage.save(&persistent);
+                }
+                if sleep_enabled && now >= last_activity + Duration::from_secs(SLEEP_TIMEOUT_SECS) {
+                    info!("No activity for {SLEEP_TIMEOUT_SECS} s, going to deep sleep");
+                    ws.shutdown().await;
+                    if led_save_at.take().is_some() {
+                        storage.save(&persistent);
+                    }
+                    led.set(LedCmd::Off).await;
+                    /* The led keeps its color while sleeping, let the led task fade it out first */
+                    Timer::after_millis(SLEEP_LED_OFF_DELAY_MS).await;
+                    deep_sleep(&mut rtc);
+                }
+                if now >= next_rssi_check {
+                    next_rssi_check = now + Duration::from_millis(RSSI_REPORT_PERIOD_MS);
+                    if connection_state() == ConnectionState::WsConnected
+                        && let Some(rssi) = rssi()
+                        && reported_rssi.is_none_or(|reported| {
+                            reported.abs_diff(rssi) > RSSI_REPORT_THRESHOLD_DB
+                        })
+                    {
+                        match ws.send_rssi(rssi) {
+                            Ok(()) => reported_rssi = Some(rssi),
+                            Err(e) => warn!("Dropping RSSI report: {e}"),
+                        }
+                    }
+                    #[cfg(feature = "battery")]
+                    if now >= next_battery_report
+                        && let Some(percent) = battery.percent()
+                    {
+                        next_battery_report = now + Duration::from_secs(BATTERY_REPORT_PERIOD_SECS);
+                        if connection_state() == ConnectionState::WsConnected
+                            && let Err(e) = ws.send_battery(percent)
+                        {
+                            warn!("Dropping battery report: {e}");
+                        }
+                        if percent <= LOW_BATTERY_PERCENT {
+                            warn!("Low battery: {percent}%");
+                            led.set(
+                                persistent
+                                    .config
+                                    .event_led(LedEvent::Alert, LOW_BATTERY_LED),
+                            )
+                            .await;
+                        }
+                    }
+                }
+                if grace_led && now >= grace_until {
+                    grace_led = false;
+                    /* Without any led to restore, the connection indicator is shown */
+                    let idle = match presets.get(PresetSlot::Idle).unwrap_or(last_led) {
+                        LedCmd::Off => CONNECTED_LED,
+                        idle => idle,
+                    };
+                    led.set(persistent.config.event_led(LedEvent::Idle, idle))
+                        .await;
+                }
+                if let Some(mut pending) = unacked.take_if(|pending| now >= pending.deadline) {
+                    if connection_state() != ConnectionState::WsConnected {
+                        info!("Buzz {} not acknowledged before disconnection", pending.seq);
+                    } else if pending.retransmissions < BUZZ_MAX_RETRANSMISSIONS {
+                        info!("Buzz {} not acknowledged, sending it again", pending.seq);
+                        match ws.resend_button_pushed(
+                            pending.seq,
+                            persistent.config.tiebreak,
+                            pending.simulated,
+                            pending.pressed_at,
+                        ) {
+                            Ok(()) => {
+                                pending.retransmissions += 1;
+                                pending.deadline = now + Duration::from_millis(BUZZ_ACK_TIMEOUT_MS);
+                                unacked = Some(pending);
+                            }
+                            Err(e) => warn!("Failed to send buzz {} again: {e}", pending.seq),
+                        }
+                    } else {
+                        warn!("Buzz {} never acknowledged by NBC", pending.seq);
+                        led.set(
+                            persistent
+                                .config
+                                .event_led(LedEvent::Alert, BUZZ_DROPPED_LED),
+                        )
+                        .await;
+                    }
+                }
             }
-            Either::First(WebsocketEvent::Disconnected) => {
+            Either4::First(WebsocketEvent::Disconnected) => {
                 info!("Buzzer is now disconnected from NBC");
-                led.set(connecting_blink).await;
+                grace_led = false;
+                /* The red flashes are transient, falling back to the connecting led once done */
+                led.set(persistent.config.event_led(LedEvent::Idle, CONNECTING_LED))
+                    .await;
+                led.set(
+                    persistent
+                        .config
+                        .event_led(LedEvent::Alert, DISCONNECTED_LED),
+                )
+                .await;
+            }
+            Either4::First(WebsocketEvent::AddressTimeout) => {
+                led.set(
+                    persistent
+                        .config
+                        .event_led(LedEvent::Alert, ADDRESS_TIMEOUT_LED),
+                )
+                .await;
             }
-            Either::First(WebsocketEvent::Command(cmd)) => {
+            Either4::First(WebsocketEvent::Command(Command::Led(cmd))) => {
+                grace_led = false;
+                if !matches!(cmd, LedCmd::Off) && !cmd.is_transient() {
+                    let stored = StoredLedCmd::from(cmd);
+                    if persistent.last_led.as_ref() != Some(&stored) {
+                        persistent.last_led = Some(stored);
+                        led_save_at =
+                            Some(Instant::now() + Duration::from_secs(LED_SAVE_DELAY_SECS));
+                    }
+                    last_led = cmd;
+                }
                 led.set(cmd).await;
             }
-            Either::Second(_) => {
-                ws.send_button_pushed().await;
+            Either4::First(WebsocketEvent::Command(Command::Status)) => {
+                if let Err(e) = ws.send_status(DeviceStatus {
+                    boot_count: persistent.boot_count,
+                    crash_count: persistent.crash_count,
+                    rejected_buzzes,
+                    temperature: thermometer.read().await,
+                    led_pattern: led.current_pattern(),
+                    locked: button.is_locked(),
+                }) {
+                    warn!("Dropping status reply: {e}");
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::Config(update))) => {
+                match persistent.config.apply(&update) {
+                    Ok(config) => {
+                        info!("Applying new configuration");
+                        persistent.config = config;
+                        storage.save(&persistent);
+                        apply_config(&mut led, &presets, &persistent.config).await;
+                    }
+                    Err(e) => warn!("Rejected configuration update: {e}"),
+                }
+                if let Err(e) = ws.send_config(persistent.config.clone()) {
+                    warn!("Dropping config reply: {e}");
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::ExportState)) => {
+                if let Err(e) = ws.send_state(persistent.config.export()) {
+                    warn!("Dropping state reply: {e}");
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::ImportState(state))) => {
+                match persistent.config.import(state) {
+                    Ok(config) => {
+                        info!("Importing configuration");
+                        persistent.config = config;
+                        storage.save(&persistent);
+                        apply_config(&mut led, &presets, &persistent.config).await;
+                    }
+                    Err(e) => warn!("Rejected configuration import: {e}"),
+                }
+                if let Err(e) = ws.send_state(persistent.config.export()) {
+                    warn!("Dropping state reply: {e}");
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::LogConfig(update))) => {
+                persistent.config.log = persistent.config.log.apply(&update);
+                storage.save(&persistent);
+                logger::set_categories(&persistent.config.log);
+                if let Err(e) = ws.send_config(persistent.config.clone()) {
+                    warn!("Dropping config reply: {e}");
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::HueShift(degrees_per_sec))) => {
+                led.set_hue_shift(degrees_per_sec);
+            }
+            #[cfg(feature = "debug-commands")]
+            Either4::First(WebsocketEvent::Command(Command::DumpTable)) => {
+                if let Err(e) = ws.send_table(&led.current_table()) {
+                    warn!("Dropping brightness table reply: {e}");
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::WifiReconnect)) => {
+                info!("Wifi reconnection requested by NBC");
+                request_reconnect();
+            }
+            Either4::First(WebsocketEvent::Command(Command::WifiCredentials(networks))) => {
+                match check_networks(&networks) {
+                    Ok(()) => {
+                        info!(
+                            "Wifi credentials provisioned for {} access points",
+                            networks.len()
+                        );
+                        persistent.wifi = networks.clone();
+                        storage.save(&persistent);
+                        set_networks(networks);
+                    }
+                    Err(e) => warn!("Rejected wifi credentials: {e}"),
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::Lock(locked))) => {
+                info!("Button {}", if locked { "locked" } else { "unlocked" });
+                button.set_locked(locked);
+            }
+            Either4::First(WebsocketEvent::Command(Command::Ack(seq))) => {
+                if unacked.take_if(|pending| pending.seq == seq).is_none() {
+                    debug!("Ignoring acknowledgement of buzz {seq}");
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::Identify)) => {
+                if let Err(e) = ws.send_identify(&persistent.config.name) {
+                    warn!("Dropping identification: {e}");
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::Reboot)) => {
+                info!("Reboot requested by NBC");
+                ws.shutdown().await;
+                if led_save_at.take().is_some() {
+                    storage.save(&persistent);
+                }
+                esp_hal::system::software_reset();
+            }
+            Either4::First(WebsocketEvent::Command(Command::Tone(cmd))) => buzzer.play(cmd),
+            Either4::First(WebsocketEvent::Command(Command::Sleep(enabled))) => {
+                info!(
+                    "Deep sleep on inactivity {}",
+                    if enabled { "enabled" } else { "disabled" }
+                );
+                sleep_enabled = enabled;
+            }
+            Either4::First(WebsocketEvent::Command(Command::Temperature)) => {
+                if let Err(e) = ws.send_temperature(thermometer.read().await) {
+                    warn!("Dropping temperature reply: {e}");
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::ButtonLevel)) => {
+                if let Err(e) = ws.send_button_level(button.read_level().await) {
+                    warn!("Dropping button level reply: {e}");
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::SetPreset(slot, cmd))) => {
+                info!("Storing preset for {slot:?}");
+                presets.set(slot, cmd);
+                if slot == PresetSlot::Idle {
+                    led.set_idle(persistent.config.event_led(LedEvent::Idle, cmd));
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::TriggerPreset(slot))) => {
+                match presets.get(slot) {
+                    Some(cmd) => {
+                        grace_led = false;
+                        led.set(persistent.config.event_led(slot.event(), cmd))
+                            .await
+                    }
+                    None => warn!("No preset stored for {slot:?}"),
+                }
+            }
+            Either4::Second(ButtonEvent::Released { held }) => {
+                if connection_state() == ConnectionState::WsConnected
+                    && let Err(e) = ws.send_button_released(held)
+                {
+                    warn!("Dropping button release: {e}");
+                }
+            }
+            Either4::Second(ButtonEvent::DoublePress) => {
+                info!("Ignoring double press");
+            }
+            Either4::Second(ButtonEvent::LongPress { .. }) => {
+                if connection_state() == ConnectionState::WsConnected {
+                    info!("Long press, identifying again");
+                    if let Err(e) = ws.send_identify(&persistent.config.name) {
+                        warn!("Dropping identification: {e}");
+                    }
+                } else {
+                    /* E.g. right after the access point restarted: no need to wait for the
+                     * pending reconnection delays
+                     */
+                    info!("Long press, reconnecting to wifi");
+                    request_reconnect();
+                }
+            }
+            Either4::First(WebsocketEvent::Command(Command::SimulateBuzz))
+                if !persistent.config.allow_simulated_buzz =>
+            {
+                warn!("Ignoring simulated buzz, disabled by configuration");
+            }
+            /* Physical presses are already filtered by the button task while locked */
+            Either4::First(WebsocketEvent::Command(Command::SimulateBuzz))
+                if button.is_locked() =>
+            {
+                info!("Ignoring simulated buzz, button is locked");
+            }
+            Either4::First(WebsocketEvent::Command(Command::SimulateBuzz))
+            | Either4::Second(ButtonEvent::Pressed { .. })
+                if Instant::now() < grace_until =>
+            {
+                info!("Ignoring button push during startup grace period");
+            }
+            Either4::First(WebsocketEvent::Command(Command::SimulateBuzz))
+            | Either4::Second(ButtonEvent::Pressed { .. })
+                if last_buzz
+                    .is_some_and(|t| t.elapsed() < Duration::from_millis(MIN_INTER_BUZZ_MS)) =>
+            {
+                rejected_buzzes = rejected_buzzes.wrapping_add(1);
+                warn!("Rejecting buzz too close to the previous one");
+            }
+            Either4::First(WebsocketEvent::Command(Command::SimulateBuzz)) => {
+                let now = Instant::now();
+                last_buzz = Some(now);
+                if let Some(pending) =
+                    buzz(&mut led, &mut ws, &presets, &persistent.config, true, now).await
+                {
+                    unacked = Some(pending);
+                }
+            }
+            Either4::Second(ButtonEvent::Pressed { at }) => {
+                last_buzz = Some(at);
+                if let Some(pending) =
+                    buzz(&mut led, &mut ws, &presets, &persistent.config, false, at).await
+                {
+                    unacked = Some(pending);
+                }
+                if persistent.config.sound_enabled {
+                    buzzer.play(BUZZ_TONE);
+                }
+                #[cfg(feature = "latency-stats")]
+                buzz_latency.record(at);
             }
         }
     }
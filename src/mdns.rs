@@ -0,0 +1,167 @@
+use core::fmt::Write as _;
+use core::net::Ipv4Addr;
+
+use embassy_net::{
+    HardwareAddress, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use heapless::String;
+use log::{debug, error, info, warn};
+use static_cell::StaticCell;
+
+/* Minimal mDNS responder: only A (and ANY) queries for our own hostname are answered, so that the
+ * host can find buzzers on the LAN as buzzer-<last 3 MAC bytes>.local
+ */
+const MDNS_ADDRESS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const HOSTNAME_PREFIX: &str = "buzzer-";
+const HOSTNAME_MAX_LEN: usize = 13;
+const BUF_SIZE: usize = 512;
+const DNS_HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/* Top bit of the class in answers: the record replaces any cached one */
+const CLASS_CACHE_FLUSH: u16 = 0x8000;
+const RECORD_TTL_SECS: u32 = 120;
+
+static RX_BUFFER: StaticCell<[u8; BUF_SIZE]> = StaticCell::new();
+static TX_BUFFER: StaticCell<[u8; BUF_SIZE]> = StaticCell::new();
+static RX_META: StaticCell<[PacketMetadata; 4]> = StaticCell::new();
+static TX_META: StaticCell<[PacketMetadata; 4]> = StaticCell::new();
+
+/// Hostname announced over mDNS (without the .local domain), derived from the device MAC
+pub fn hostname(mac: HardwareAddress) -> String<HOSTNAME_MAX_LEN> {
+    let mut hostname = String::new();
+    let _ = hostname.push_str(HOSTNAME_PREFIX);
+    for byte in &mac.as_bytes()[3..6] {
+        /* Can not overflow, the prefix and 3 bytes fit in the hostname */
+        let _ = write!(hostname, "{byte:02x}");
+    }
+    hostname
+}
+
+/// Whether a DNS query asks for the address of the given host, in the .local domain. Returns the
+/// offset following the question on success.
+fn question_matches(packet: &[u8], offset: usize, hostname: &str) -> Option<(bool, usize)> {
+    let mut offset = offset;
+    let mut labels = [hostname, "local"].into_iter();
+    let mut matches = true;
+    loop {
+        let len = usize::from(*packet.get(offset)?);
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        /* Compressed names are not expected in questions, give up on the packet */
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = packet.get(offset..offset + len)?;
+        offset += len;
+        matches &= labels
+            .next()
+            .is_some_and(|expected| expected.as_bytes().eq_ignore_ascii_case(label));
+    }
+    matches &= labels.next().is_none();
+    let fields = packet.get(offset..offset + 4)?;
+    let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+    let qclass = u16::from_be_bytes([fields[2], fields[3]]) & !CLASS_CACHE_FLUSH;
+    matches &= (qtype == TYPE_A || qtype == TYPE_ANY) && qclass == CLASS_IN;
+    Some((matches, offset + 4))
+}
+
+/// Whether an mDNS packet is a query for our address
+fn is_query_for(packet: &[u8], hostname: &str) -> bool {
+    if packet.len() < DNS_HEADER_LEN {
+        return false;
+    }
+    let flags = u16::from_be_bytes([packet[2], packet[3]]);
+    /* Responses from other hosts are ignored */
+    if flags & 0x8000 != 0 {
+        return false;
+    }
+    let questions = u16::from_be_bytes([packet[4], packet[5]]);
+    let mut offset = DNS_HEADER_LEN;
+    for _ in 0..questions {
+        match question_matches(packet, offset, hostname) {
+            Some((true, _)) => return true,
+            Some((false, next)) => offset = next,
+            None => return false,
+        }
+    }
+    false
+}
+
+/// Build an authoritative answer with the A record of our hostname
+fn write_answer(buf: &mut [u8], hostname: &str, address: Ipv4Addr) -> Option<usize> {
+    let mut len = 0;
+    let mut put = |bytes: &[u8]| {
+        buf.get_mut(len..len + bytes.len())?.copy_from_slice(bytes);
+        len += bytes.len();
+        Some(())
+    };
+    /* Id 0, response + authoritative, no question, one answer */
+    put(&[0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0])?;
+    for label in [hostname, "local"] {
+        put(&[label.len() as u8])?;
+        put(label.as_bytes())?;
+    }
+    put(&[0])?;
+    put(&TYPE_A.to_be_bytes())?;
+    put(&(CLASS_IN | CLASS_CACHE_FLUSH).to_be_bytes())?;
+    put(&RECORD_TTL_SECS.to_be_bytes())?;
+    put(&4u16.to_be_bytes())?;
+    put(&address.octets())?;
+    Some(len)
+}
+
+#[embassy_executor::task]
+pub async fn mdns_task(stack: Stack<'static>, mac: HardwareAddress) {
+    let hostname = hostname(mac);
+    let rx_buffer = RX_BUFFER.init([0u8; BUF_SIZE]);
+    let tx_buffer = TX_BUFFER.init([0u8; BUF_SIZE]);
+    let rx_meta = RX_META.init([PacketMetadata::EMPTY; 4]);
+    let tx_meta = TX_META.init([PacketMetadata::EMPTY; 4]);
+    let mut socket = UdpSocket::new(stack, rx_meta, rx_buffer, tx_meta, tx_buffer);
+    if let Err(e) = socket.bind(MDNS_PORT) {
+        error!("Failed to bind mDNS socket: {e:?}");
+        return;
+    }
+    let mut packet = [0u8; BUF_SIZE];
+    loop {
+        if !stack.is_config_up() {
+            stack.wait_config_up().await;
+        }
+        /* Joining again after a reconfiguration is harmless */
+        if let Err(e) = stack.join_multicast_group(MDNS_ADDRESS) {
+            warn!("Failed to join mDNS multicast group: {e:?}");
+        }
+        info!("Announcing {hostname}.local over mDNS");
+        while stack.is_config_up() {
+            let len = match socket.recv_from(&mut packet).await {
+                Ok((len, _)) => len,
+                Err(e) => {
+                    debug!("Failed to receive mDNS packet: {e:?}");
+                    continue;
+                }
+            };
+            if !is_query_for(&packet[..len], &hostname) {
+                continue;
+            }
+            let Some(config) = stack.config_v4() else {
+                continue;
+            };
+            let Some(len) = write_answer(&mut packet, &hostname, config.address.address()) else {
+                continue;
+            };
+            debug!("Answering mDNS query for {hostname}.local");
+            if let Err(e) = socket
+                .send_to(&packet[..len], (MDNS_ADDRESS, MDNS_PORT))
+                .await
+            {
+                debug!("Failed to send mDNS answer: {e:?}");
+            }
+        }
+    }
+}
@@ -1,13 +1,197 @@
-use embassy_net::Runner;
+use core::cell::{Cell, RefCell};
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicI8, Ordering};
+
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_net::{Ipv4Cidr, Runner, StaticConfigV4};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
 use embassy_time::{Duration, Timer};
-use esp_radio::wifi::{Config, Interface, WifiController, sta::StationConfig};
-use log::info;
+use esp_radio::wifi::{
+    Config, Interface, PowerSaveMode, WifiController, WifiError, sta::StationConfig,
+};
+use heapless::String;
+use log::{debug, info};
+
+use crate::error::ConfigError;
+use crate::state::{ConnectionState, set_connection_state};
+use crate::wifi::{PASSWORD_MAX_LEN, SSID_MAX_LEN, WifiCredentials, WifiNetworks, WifiSettings};
+
+/* Credentials used until others are provisioned at runtime */
+const BUILD_SSID: Option<&str> = option_env!("NBC_SSID");
+const BUILD_PASSWORD: Option<&str> = option_env!("NBC_PASSWORD");
+const BUILD_FALLBACK_SSID: Option<&str> = option_env!("NBC_FALLBACK_SSID");
+const BUILD_FALLBACK_PASSWORD: Option<&str> = option_env!("NBC_FALLBACK_PASSWORD");
 
-const SSID: &str = env!("NBC_SSID");
-const PASSWORD: &str = env!("NBC_PASSWORD");
+/* Static IP configuration, e.g. "192.168.4.20/24": DHCP is used when unset */
+const STATIC_IP: Option<&str> = option_env!("NBC_STATIC_IP");
+const STATIC_GATEWAY: Option<&str> = option_env!("NBC_STATIC_GATEWAY");
+const STATIC_DNS: Option<&str> = option_env!("NBC_STATIC_DNS");
 
 const RECONNECT_DELAY_MS: u64 = 5000;
+/* Modem sleep between beacons reduces power draw and heat, at the cost of up to a beacon interval
+ * (~100 ms) of extra latency on received frames, and tens of milliseconds on average for buzz
+ * delivery. PowerSaveMode::None keeps the radio always on, for latency-sensitive setups. The
+ * keepalive and ping intervals being several seconds long, they are not affected.
+ */
+const POWER_SAVE_MODE: PowerSaveMode = PowerSaveMode::Minimum;
+/* An association attempt not completed in time moves on to the next access point */
+const CONNECT_TIMEOUT_SECS: u64 = 15;
 const RADIO_RETRY_DELAY_MS: u64 = 1000;
+const RSSI_SAMPLE_PERIOD_MS: u64 = 500;
+/* Weight of a new sample in the filtered RSSI, out of 8 */
+const RSSI_FILTER_WEIGHT: i16 = 2;
+/* Filtered RSSI of the access point in dBm, 0 meaning not connected */
+static RSSI: AtomicI8 = AtomicI8::new(0);
+
+static WIFI_SETTINGS: Mutex<CriticalSectionRawMutex, Cell<WifiSettings>> =
+    Mutex::new(Cell::new(WifiSettings {
+        channel: None,
+        bssid: None,
+    }));
+
+/* No association is attempted until credentials are set */
+static NETWORKS: Mutex<CriticalSectionRawMutex, RefCell<WifiNetworks>> =
+    Mutex::new(RefCell::new(heapless::Vec::new()));
+
+static RECONNECT_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Ask the connection task to drop the current association (if any) and to reconnect right away,
+/// skipping any pending retry delay
+pub fn request_reconnect() {
+    RECONNECT_REQUEST.signal(());
+}
+
+/// Wait for the given delay, unless a reconnection is requested in the meantime
+async fn retry_delay(delay_ms: u64) {
+    if let Either::Second(_) = select(
+        Timer::after(Duration::from_millis(delay_ms)),
+        RECONNECT_REQUEST.wait(),
+    )
+    .await
+    {
+        info!("Immediate reconnection requested");
+    }
+}
+
+fn build_network(ssid: &str, password: &str) -> Result<WifiCredentials, ConfigError> {
+    let credentials = WifiCredentials {
+        ssid: String::try_from(ssid).map_err(|_| ConfigError::SsidTooLong {
+            max_len: SSID_MAX_LEN,
+        })?,
+        password: String::try_from(password).map_err(|_| ConfigError::PasswordTooLong {
+            max_len: PASSWORD_MAX_LEN,
+        })?,
+    };
+    credentials.check()?;
+    Ok(credentials)
+}
+
+/// Access points set at build time, used until others are provisioned: the primary one, and an
+/// optional fallback one
+pub fn build_networks() -> Result<WifiNetworks, ConfigError> {
+    let mut networks = WifiNetworks::new();
+    let primary = build_network(
+        BUILD_SSID.unwrap_or_default(),
+        BUILD_PASSWORD.unwrap_or_default(),
+    )?;
+    /* Can not overflow, at most two networks are set at build time */
+    let _ = networks.push(primary);
+    if let Some(ssid) = BUILD_FALLBACK_SSID {
+        let fallback = build_network(ssid, BUILD_FALLBACK_PASSWORD.unwrap_or_default())?;
+        let _ = networks.push(fallback);
+    }
+    Ok(networks)
+}
+
+/// Set the access points used for the next association attempts. Changed access points are
+/// applied right away, by reconnecting.
+pub fn set_networks(networks: WifiNetworks) {
+    let previous = NETWORKS.lock(|n| n.replace(networks.clone()));
+    if previous != networks {
+        request_reconnect();
+    }
+}
+
+/// IP configuration of the station: the static one when configured at build time, which skips
+/// DHCP discovery, DHCP otherwise
+pub fn ip_config() -> Result<embassy_net::Config, ConfigError> {
+    let Some(address) = STATIC_IP else {
+        return Ok(embassy_net::Config::dhcpv4(Default::default()));
+    };
+    let parse = |address: &str| {
+        address
+            .parse::<Ipv4Addr>()
+            .map_err(|_| ConfigError::InvalidStaticIp)
+    };
+    let (address, prefix_len) = address
+        .split_once('/')
+        .ok_or(ConfigError::InvalidStaticIp)?;
+    let prefix_len = prefix_len
+        .parse::<u8>()
+        .ok()
+        .filter(|len| *len <= 32)
+        .ok_or(ConfigError::InvalidStaticIp)?;
+    let mut config = StaticConfigV4 {
+        address: Ipv4Cidr::new(parse(address)?, prefix_len),
+        gateway: STATIC_GATEWAY.map(parse).transpose()?,
+        dns_servers: Default::default(),
+    };
+    if let Some(dns) = STATIC_DNS {
+        /* Can not overflow, a single server is configured */
+        let _ = config.dns_servers.push(parse(dns)?);
+    }
+    Ok(embassy_net::Config::ipv4_static(config))
+}
+
+/// Filtered signal strength of the access point in dBm, if connected
+pub fn rssi() -> Option<i8> {
+    match RSSI.load(Ordering::Relaxed) {
+        0 => None,
+        rssi => Some(rssi),
+    }
+}
+
+fn update_rssi(sample: i32) {
+    let sample = sample.clamp(i8::MIN.into(), -1) as i16;
+    let filtered = match rssi() {
+        None => sample,
+        Some(previous) => {
+            (i16::from(previous) * (8 - RSSI_FILTER_WEIGHT) + sample * RSSI_FILTER_WEIGHT) / 8
+        }
+    };
+    RSSI.store(filtered as i8, Ordering::Relaxed);
+}
+
+/// Update the settings used for the next association attempts. Changed settings are applied
+/// right away, by reconnecting to the access point.
+pub fn set_wifi_settings(settings: WifiSettings) {
+    if WIFI_SETTINGS.lock(|s| s.replace(settings)) != settings {
+        request_reconnect();
+    }
+}
+
+/// Apply a new station configuration. Some radio versions ignore (or reject) configuration
+/// changes while the controller is started, so it is stopped first and restarted afterwards.
+async fn apply_config(
+    controller: &mut WifiController<'static>,
+    config: &Config,
+) -> Result<(), WifiError> {
+    let started = controller.is_started()?;
+    if started {
+        info!("Stopping wifi controller to apply the new configuration");
+        controller.stop_async().await?;
+    }
+    info!("Applying wifi configuration");
+    controller.set_config(config)?;
+    if started {
+        info!("Restarting wifi controller");
+        controller.start_async().await?;
+    }
+    Ok(())
+}
 
 #[embassy_executor::task]
 pub async fn net_task(mut runner: Runner<'static, Interface<'static>>) {
@@ -16,31 +200,116 @@ pub async fn net_task(mut runner: Runner<'static, Interface<'static>>) {
 
 #[embassy_executor::task]
 pub async fn connection(mut controller: WifiController<'static>) {
+    /* Settings and credentials of the configuration currently applied to the controller */
+    let mut applied: Option<(WifiSettings, WifiCredentials)> = None;
+    /* Index of the access point currently tried */
+    let mut current = 0;
     loop {
         if controller.is_connected() {
-            // wait until we're no longer connected
-            let _ = controller.wait_for_disconnect_async().await;
-            Timer::after(Duration::from_millis(RECONNECT_DELAY_MS)).await
+            // wait until we're no longer connected, or until a reconnection is requested, while
+            // regularly sampling the signal strength
+            match select3(
+                controller.wait_for_disconnect_async(),
+                RECONNECT_REQUEST.wait(),
+                Timer::after(Duration::from_millis(RSSI_SAMPLE_PERIOD_MS)),
+            )
+            .await
+            {
+                Either3::First(Err(e)) => {
+                    info!("Failed to wait for wifi disconnection: {e:?}");
+                    retry_delay(RECONNECT_DELAY_MS).await
+                }
+                Either3::First(Ok(_)) => {
+                    RSSI.store(0, Ordering::Relaxed);
+                    set_connection_state(&[], ConnectionState::WifiDown);
+                    retry_delay(RECONNECT_DELAY_MS).await
+                }
+                Either3::Second(_) => {
+                    info!("Immediate reconnection requested, disconnecting from access point");
+                    if let Err(e) = controller.disconnect_async().await {
+                        info!("Failed to disconnect from wifi: {e:?}");
+                    }
+                    RSSI.store(0, Ordering::Relaxed);
+                    set_connection_state(&[], ConnectionState::WifiDown);
+                }
+                Either3::Third(_) => {
+                    match controller.rssi() {
+                        Ok(sample) => update_rssi(sample),
+                        Err(e) => debug!("Failed to read RSSI: {e:?}"),
+                    }
+                    continue;
+                }
+            }
         }
 
-        let station_config = Config::Station(
-            StationConfig::default()
-                .with_ssid(SSID)
-                .with_password(PASSWORD.into()),
-        );
-        if let Err(e) = controller.set_config(&station_config) {
-            info!("Failed to configure radio stack: {e:?}, retrying...");
-            Timer::after(Duration::from_millis(RADIO_RETRY_DELAY_MS)).await;
+        let networks = NETWORKS.lock(|n| n.borrow().clone());
+        if networks.is_empty() {
+            info!("No wifi credentials, waiting for provisioning");
+            RECONNECT_REQUEST.wait().await;
             continue;
         }
-        info!("Connecting to NBC access point...");
+        current %= networks.len();
+        let credentials = networks[current].clone();
+        /* The association hints only describe the primary access point */
+        let settings = if current == 0 {
+            WIFI_SETTINGS.lock(Cell::get)
+        } else {
+            WifiSettings::default()
+        };
+        if applied.as_ref() != Some(&(settings, credentials.clone())) {
+            let mut station = StationConfig::default()
+                .with_ssid(credentials.ssid.as_str())
+                .with_password(credentials.password.as_str().into());
+            if let Some(channel) = settings.channel {
+                info!("Using fixed wifi channel {channel}");
+                station = station.with_channel(channel);
+            }
+            if let Some(bssid) = settings.bssid {
+                station = station.with_bssid(bssid);
+            }
+            let station_config = Config::Station(station);
+            if let Err(e) = apply_config(&mut controller, &station_config).await {
+                info!("Failed to configure radio stack: {e:?}, retrying...");
+                applied = None;
+                retry_delay(RADIO_RETRY_DELAY_MS).await;
+                continue;
+            }
+            applied = Some((settings, credentials));
+        }
+        info!("Connecting to NBC access point {}...", credentials.ssid);
 
-        match controller.connect_async().await {
-            Ok(_) => info!("Connected to NBC access point"),
-            Err(e) => {
+        let failed = match select(
+            controller.connect_async(),
+            Timer::after(Duration::from_secs(CONNECT_TIMEOUT_SECS)),
+        )
+        .await
+        {
+            Either::First(Ok(_)) => {
+                info!("Connected to NBC access point {}", credentials.ssid);
+                if let Err(e) = controller.set_power_saving(POWER_SAVE_MODE) {
+                    info!("Failed to set wifi power saving mode: {e:?}");
+                }
+                set_connection_state(&[ConnectionState::WifiDown], ConnectionState::WifiUp);
+                false
+            }
+            Either::First(Err(e)) => {
                 info!("Failed to connect to wifi: {e:?}");
-                Timer::after(Duration::from_millis(RECONNECT_DELAY_MS)).await
+                true
+            }
+            Either::Second(_) => {
+                info!("Timed out connecting to wifi");
+                if let Err(e) = controller.disconnect_async().await {
+                    debug!("Failed to abort wifi connection: {e:?}");
+                }
+                true
+            }
+        };
+        if failed {
+            if networks.len() > 1 {
+                current = (current + 1) % networks.len();
+                info!("Trying access point {} next", networks[current].ssid);
             }
+            retry_delay(RECONNECT_DELAY_MS).await
         }
     }
 }
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use serde_json_core as sj;
+
+use crate::config::Config;
+use crate::led_cmd::StoredLedCmd;
+use crate::wifi::{PASSWORD_MAX_LEN, WifiNetworks};
+
+/* Persistent data is stored as a single JSON record at the start of the nvs partition, prefixed
+ * by a small header (magic + payload length). The esp-idf NVS format is not used by this firmware,
+ * so we own the whole partition.
+ */
+pub const STORAGE_MAGIC: u32 = 0x4e42_4231;
+pub const HEADER_LEN: usize = 8;
+/* One flash sector. Strings are escaped in JSON, up to 6 bytes per character for control
+ * characters, so the record must hold the longest name and credentials fully escaped: the tests
+ * check that the largest possible data still fits.
+ */
+pub const RECORD_SIZE: usize = 4096;
+/* The payload length is stored on 16 bits in the header */
+const _: () = assert!(RECORD_SIZE - HEADER_LEN <= u16::MAX as usize);
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct PersistentData {
+    pub boot_count: u32,
+    pub crash_count: u32,
+    pub config: Config,
+    /// Last persistent pattern received from the host, restored on boot
+    pub last_led: Option<StoredLedCmd>,
+    /// Access points provisioned by the host, replacing the build-time ones when not empty
+    pub wifi: WifiNetworks,
+}
+
+impl PersistentData {
+    /// Decode the JSON payload of a record. Strings are unescaped, since names and credentials
+    /// may hold quotes or other characters escaped when encoding.
+    pub fn decode(payload: &[u8]) -> Result<Self, sj::de::Error> {
+        /* Large enough for the longest string of the record, a wifi password */
+        let mut unescaped = [0u8; PASSWORD_MAX_LEN];
+        sj::from_slice_escaped(payload, &mut unescaped).map(|(data, _)| data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::String;
+
+    use super::*;
+    use crate::config::{LogCategories, NAME_MAX_LEN, OfflineBuzzPolicy};
+    use crate::led_cmd::{LED_COUNT, MAX_SEQUENCE_COLORS};
+    use crate::wifi::{MAX_WIFI_NETWORKS, PASSWORD_MAX_LEN, SSID_MAX_LEN, WifiCredentials};
+
+    /// String filled with control characters, the ones taking the most room once escaped
+    fn escaped<const N: usize>() -> String<N> {
+        core::iter::repeat_n('\u{1}', N).collect()
+    }
+
+    fn largest_led() -> StoredLedCmd {
+        let rgb = "[255,255,255]";
+        let json = format!(
+            r#"{{"t":"sequence","c":{rgb},"d":{max},"p":{max},"fi":{max},"fo":{max},"dc":255,"b":255,"k":65535,"r":65535,"sh":"triangle","cs":[{cs}],"f":[{f}]}}"#,
+            max = u32::MAX,
+            cs = [rgb; MAX_SEQUENCE_COLORS].join(","),
+            f = [rgb; LED_COUNT].join(","),
+        );
+        sj::from_str::<StoredLedCmd>(&json)
+            .expect("valid stored pattern")
+            .0
+    }
+
+    fn largest_data() -> PersistentData {
+        let credentials = WifiCredentials {
+            ssid: escaped::<SSID_MAX_LEN>(),
+            password: escaped::<PASSWORD_MAX_LEN>(),
+        };
+        PersistentData {
+            boot_count: u32::MAX,
+            crash_count: u32::MAX,
+            config: Config {
+                name: escaped::<NAME_MAX_LEN>(),
+                brightness: u8::MAX,
+                led_enabled: false,
+                idle_brightness: u8::MAX,
+                buzz_brightness: u8::MAX,
+                alert_brightness: u8::MAX,
+                tiebreak: Some(u32::MAX),
+                log: LogCategories {
+                    network: false,
+                    ws: false,
+                    led: false,
+                    button: false,
+                },
+                allow_simulated_buzz: false,
+                wifi_channel: Some(u8::MAX),
+                wifi_bssid: Some([u8::MAX; 6]),
+                offline_buzz: OfflineBuzzPolicy::Buffer,
+                buzz_ack: false,
+                sound_enabled: false,
+            },
+            last_led: Some(largest_led()),
+            wifi: core::iter::repeat_n(credentials, MAX_WIFI_NETWORKS).collect(),
+        }
+    }
+
+    #[test]
+    fn largest_data_fits_in_a_record() {
+        let data = largest_data();
+        let mut payload = [0u8; RECORD_SIZE - HEADER_LEN];
+        let len = sj::to_slice(&data, &mut payload).expect("data fits in a record");
+        let decoded = PersistentData::decode(&payload[..len]).expect("valid record");
+        assert_eq!(decoded.config.name, data.config.name);
+        assert_eq!(decoded.last_led, data.last_led);
+        assert_eq!(decoded.wifi, data.wifi);
+    }
+}
@@ -0,0 +1,57 @@
+use crate::config::LedEvent;
+use crate::error::CommandError;
+use crate::led_cmd::LedCmd;
+
+/// Named slots holding LED patterns preloaded by the host, so that they show without a round-trip
+/// once the corresponding event occurs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresetSlot {
+    /// Played locally as soon as the button is pushed
+    OnBuzz,
+    /// Played when triggered by the host, the buzzer not knowing whether an answer is right
+    OnCorrect,
+    /// Played when triggered by the host, like `OnCorrect`
+    OnWrong,
+    /// Played locally whenever no other pattern runs
+    Idle,
+}
+
+const PRESET_SLOT_COUNT: usize = 4;
+
+impl PresetSlot {
+    /// Category of the event the slot is played for, which sets its brightness
+    pub fn event(&self) -> LedEvent {
+        match self {
+            PresetSlot::OnBuzz | PresetSlot::OnCorrect | PresetSlot::OnWrong => LedEvent::Buzz,
+            PresetSlot::Idle => LedEvent::Idle,
+        }
+    }
+}
+
+impl TryFrom<&str> for PresetSlot {
+    type Error = CommandError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "on_buzz" => Ok(PresetSlot::OnBuzz),
+            "on_correct" => Ok(PresetSlot::OnCorrect),
+            "on_wrong" => Ok(PresetSlot::OnWrong),
+            "idle" => Ok(PresetSlot::Idle),
+            _ => Err(CommandError::InvalidPresetSlot),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Presets {
+    slots: [Option<LedCmd>; PRESET_SLOT_COUNT],
+}
+
+impl Presets {
+    pub fn set(&mut self, slot: PresetSlot, cmd: LedCmd) {
+        self.slots[slot as usize] = Some(cmd);
+    }
+
+    pub fn get(&self, slot: PresetSlot) -> Option<LedCmd> {
+        self.slots[slot as usize]
+    }
+}
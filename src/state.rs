@@ -0,0 +1,46 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use log::info;
+
+/// Connection state of the buzzer, maintained by the network and websocket tasks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    WifiDown,
+    WifiUp,
+    Connecting,
+    WsConnected,
+}
+
+impl ConnectionState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnectionState::WifiDown => "wifi_down",
+            ConnectionState::WifiUp => "wifi_up",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::WsConnected => "ws_connected",
+        }
+    }
+}
+
+static CONNECTION_STATE: Mutex<CriticalSectionRawMutex, Cell<ConnectionState>> =
+    Mutex::new(Cell::new(ConnectionState::WifiDown));
+
+pub fn connection_state() -> ConnectionState {
+    CONNECTION_STATE.lock(Cell::get)
+}
+
+/// Move to a new connection state, only if the current state is one of the expected ones (or
+/// unconditionally if `from` is empty)
+pub fn set_connection_state(from: &[ConnectionState], to: ConnectionState) {
+    let previous = CONNECTION_STATE.lock(|state| {
+        let previous = state.get();
+        if from.is_empty() || from.contains(&previous) {
+            state.set(to);
+        }
+        previous
+    });
+    if previous != to && (from.is_empty() || from.contains(&previous)) {
+        info!("Connection state: {} -> {}", previous.name(), to.name());
+    }
+}
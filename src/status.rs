@@ -0,0 +1,147 @@
+use embassy_time::Instant;
+use heapless::String;
+
+use crate::config::{Config, NAME_MAX_LEN};
+use crate::error::CommandError;
+#[cfg(feature = "debug-commands")]
+use crate::led_pattern::TableEntry;
+
+/* Brightness table entries sent in each table message */
+#[cfg(feature = "debug-commands")]
+pub const TABLE_CHUNK_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceStatus {
+    pub boot_count: u32,
+    pub crash_count: u32,
+    pub rejected_buzzes: u32,
+    /// Chip temperature, in Celsius degrees
+    pub temperature: f32,
+    /// Name of the pattern currently shown by the led
+    pub led_pattern: &'static str,
+    pub locked: bool,
+}
+
+/// Message sent by the buzzer to the host
+pub enum StatusMessage {
+    Identification(String<NAME_MAX_LEN>),
+    Buzz {
+        seq: u32,
+        tiebreak: Option<u32>,
+        simulated: bool,
+        pressed_at: Instant,
+    },
+    Release {
+        held_ms: u32,
+    },
+    Status(DeviceStatus),
+    Config(Config),
+    State(Config),
+    /// Level of the button pin, high when released
+    ButtonLevel {
+        high: bool,
+    },
+    Temperature(f32),
+    Rssi(i8),
+    Battery(u8),
+    LedIdle,
+    /// Command rejected by the device
+    Error(CommandError),
+    #[cfg(feature = "debug-commands")]
+    Table {
+        offset: usize,
+        len: usize,
+        entries: heapless::Vec<TableEntry, TABLE_CHUNK_LEN>,
+    },
+}
+
+impl From<&StatusMessage> for &str {
+    fn from(value: &StatusMessage) -> Self {
+        match value {
+            StatusMessage::Identification(_) => "identification",
+            StatusMessage::Buzz { .. } => "buzz",
+            StatusMessage::Release { .. } => "release",
+            StatusMessage::Status(_) => "status",
+            StatusMessage::Config(_) => "config",
+            StatusMessage::State(_) => "state",
+            StatusMessage::ButtonLevel { .. } => "button_level",
+            StatusMessage::Temperature(_) => "temp",
+            StatusMessage::Rssi(_) => "rssi",
+            StatusMessage::Battery(_) => "battery",
+            StatusMessage::LedIdle => "led_idle",
+            StatusMessage::Error(_) => "error",
+            #[cfg(feature = "debug-commands")]
+            StatusMessage::Table { .. } => "table",
+        }
+    }
+}
+
+impl StatusMessage {
+    /// Whether a message still queued when the connection drops is worth sending in the next
+    /// session. Answers to host requests only make sense in the session they were requested in,
+    /// while buzzes are events the host must not miss.
+    pub fn outlives_session(&self) -> bool {
+        match self {
+            StatusMessage::Identification(_) => false,
+            StatusMessage::Buzz { .. } => true,
+            StatusMessage::Release { .. } => false,
+            StatusMessage::Status(_) => false,
+            StatusMessage::Config(_) => false,
+            StatusMessage::State(_) => false,
+            StatusMessage::ButtonLevel { .. } => false,
+            StatusMessage::Temperature(_) => false,
+            StatusMessage::Rssi(_) => false,
+            StatusMessage::Battery(_) => false,
+            StatusMessage::LedIdle => false,
+            StatusMessage::Error(_) => false,
+            #[cfg(feature = "debug-commands")]
+            StatusMessage::Table { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_buzzes_outlive_a_session() {
+        let status = DeviceStatus {
+            boot_count: 1,
+            crash_count: 0,
+            rejected_buzzes: 0,
+            temperature: 30.0,
+            led_pattern: "off",
+            locked: false,
+        };
+        let messages = [
+            StatusMessage::Identification(String::new()),
+            StatusMessage::Buzz {
+                seq: 1,
+                tiebreak: None,
+                simulated: false,
+                pressed_at: Instant::from_millis(0),
+            },
+            StatusMessage::Release { held_ms: 100 },
+            StatusMessage::Status(status),
+            StatusMessage::Config(Config::default()),
+            StatusMessage::State(Config::default()),
+            StatusMessage::ButtonLevel { high: true },
+            StatusMessage::Temperature(30.0),
+            StatusMessage::Rssi(-60),
+            StatusMessage::Battery(80),
+            StatusMessage::LedIdle,
+            StatusMessage::Error(CommandError::UnknownType),
+            #[cfg(feature = "debug-commands")]
+            StatusMessage::Table {
+                offset: 0,
+                len: 0,
+                entries: heapless::Vec::new(),
+            },
+        ];
+        for msg in &messages {
+            let name: &str = msg.into();
+            assert_eq!(msg.outlives_session(), name == "buzz", "{name} message");
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use embedded_storage::{ReadStorage, Storage as _};
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, PARTITION_TABLE_MAX_LEN, PartitionType,
+};
+use esp_hal::peripherals::FLASH;
+use esp_storage::FlashStorage;
+use log::{info, warn};
+use serde_json_core as sj;
+
+use crate::persistent::{HEADER_LEN, PersistentData, RECORD_SIZE, STORAGE_MAGIC};
+
+pub struct Storage {
+    flash: FlashStorage<'static>,
+    offset: Option<u32>,
+}
+
+impl Storage {
+    pub fn new(flash: FLASH<'static>) -> Self {
+        let mut flash = FlashStorage::new(flash);
+        let mut table_buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+        let offset = partitions::read_partition_table(&mut flash, &mut table_buffer)
+            .ok()
+            .and_then(|table| {
+                table
+                    .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
+                    .ok()
+                    .flatten()
+            })
+            .map(|partition| partition.offset());
+        if offset.is_none() {
+            warn!("No nvs partition found, persistent data will not be saved");
+        }
+        Storage { flash, offset }
+    }
+
+    /// Load persistent data from flash, falling back to defaults if nothing valid is stored
+    /// (e.g. on first boot)
+    pub fn load(&mut self) -> PersistentData {
+        let Some(offset) = self.offset else {
+            return PersistentData::default();
+        };
+        let mut record = [0u8; RECORD_SIZE];
+        if let Err(e) = self.flash.read(offset, &mut record) {
+            warn!("Failed to read persistent data: {e:?}");
+            return PersistentData::default();
+        }
+        let magic = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+        let len = u16::from_le_bytes([record[4], record[5]]) as usize;
+        if magic != STORAGE_MAGIC || len > RECORD_SIZE - HEADER_LEN {
+            info!("No persistent data found, using defaults");
+            return PersistentData::default();
+        }
+        match PersistentData::decode(&record[HEADER_LEN..HEADER_LEN + len]) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Failed to decode persistent data: {e}");
+                PersistentData::default()
+            }
+        }
+    }
+
+    pub fn save(&mut self, data: &PersistentData) {
+        let Some(offset) = self.offset else {
+            return;
+        };
+        let mut record = [0xffu8; RECORD_SIZE];
+        let len = match sj::to_slice(data, &mut record[HEADER_LEN..]) {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("Failed to encode persistent data: {e:?}");
+                return;
+            }
+        };
+        record[0..4].copy_from_slice(&STORAGE_MAGIC.to_le_bytes());
+        record[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+        record[6..8].copy_from_slice(&[0, 0]);
+        if let Err(e) = self.flash.write(offset, &record[..HEADER_LEN + len]) {
+            warn!("Failed to write persistent data: {e:?}");
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use embassy_time::Timer;
+use esp_hal::{
+    peripherals::TSENS,
+    tsens::{Config, TemperatureSensor},
+};
+use log::warn;
+
+/* The sensor is noisy, each reading is the average of several samples */
+const SAMPLE_COUNT: u8 = 4;
+const SAMPLE_INTERVAL_MS: u64 = 2;
+/* Above this temperature the chip is getting close to its operating limit */
+const THERMAL_WARNING_CELSIUS: f32 = 80.0;
+
+pub struct Thermometer {
+    sensor: TemperatureSensor<'static>,
+}
+
+impl Thermometer {
+    pub fn new(tsens: TSENS<'static>) -> Self {
+        Thermometer {
+            sensor: TemperatureSensor::new(tsens, Config::default())
+                .expect("Failed to initialize temperature sensor"),
+        }
+    }
+
+    /// Read the chip temperature, in Celsius degrees
+    pub async fn read(&mut self) -> f32 {
+        let mut total = 0.0;
+        for _ in 0..SAMPLE_COUNT {
+            total += self.sensor.get_temperature().to_celsius();
+            Timer::after_millis(SAMPLE_INTERVAL_MS).await;
+        }
+        let celsius = total / f32::from(SAMPLE_COUNT);
+        if celsius > THERMAL_WARNING_CELSIUS {
+            warn!("Chip temperature is high: {celsius:.1}°C");
+        }
+        celsius
+    }
+}
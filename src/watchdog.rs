@@ -0,0 +1,75 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::{Instant, Timer};
+use esp_hal::{
+    peripherals::TIMG1,
+    timer::timg::{MwdtStage, Wdt},
+};
+use log::{info, warn};
+
+/* A subsystem not reporting any activity for this long is considered hung: the hardware watchdog
+ * is not fed anymore, and resets the chip
+ */
+const LIVENESS_TIMEOUT_SECS: u32 = 30;
+/* Idle subsystems report their activity at least this often */
+pub const HEARTBEAT_SECS: u64 = 10;
+/* The hardware watchdog resets the chip when not fed for this long */
+const WATCHDOG_TIMEOUT_SECS: u64 = 5;
+const FEED_PERIOD_MS: u64 = 1000;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Subsystem {
+    Main,
+    Button,
+    Websocket,
+    Led,
+}
+
+const SUBSYSTEMS: [Subsystem; 4] = [
+    Subsystem::Main,
+    Subsystem::Button,
+    Subsystem::Websocket,
+    Subsystem::Led,
+];
+
+/* Time of the last activity of each subsystem, in seconds since boot */
+static LAST_SEEN: [AtomicU32; SUBSYSTEMS.len()] = [const { AtomicU32::new(0) }; SUBSYSTEMS.len()];
+
+fn now_secs() -> u32 {
+    Instant::now().as_secs() as u32
+}
+
+/// Report that a subsystem is alive
+pub fn feed(subsystem: Subsystem) {
+    LAST_SEEN[subsystem as usize].store(now_secs(), Ordering::Relaxed);
+}
+
+/// Feed the hardware watchdog as long as all subsystems report activity
+#[embassy_executor::task]
+pub async fn watchdog_task(mut wdt: Wdt<TIMG1<'static>>) {
+    for subsystem in SUBSYSTEMS {
+        feed(subsystem);
+    }
+    wdt.set_timeout(
+        MwdtStage::Stage0,
+        esp_hal::time::Duration::from_secs(WATCHDOG_TIMEOUT_SECS),
+    );
+    wdt.enable();
+    info!("Watchdog enabled");
+    loop {
+        let now = now_secs();
+        let hung = SUBSYSTEMS.into_iter().find(|subsystem| {
+            now.saturating_sub(LAST_SEEN[*subsystem as usize].load(Ordering::Relaxed))
+                > LIVENESS_TIMEOUT_SECS
+        });
+        if let Some(subsystem) = hung {
+            warn!(
+                "{subsystem:?} subsystem hung for more than {LIVENESS_TIMEOUT_SECS} s, letting the watchdog reset the chip"
+            );
+            /* Wait for the reset, without feeding the watchdog anymore */
+            core::future::pending::<()>().await;
+        }
+        wdt.feed();
+        Timer::after_millis(FEED_PERIOD_MS).await;
+    }
+}
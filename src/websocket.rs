@@ -1,59 +1,236 @@
+use core::fmt::Write as _;
+use core::net::Ipv4Addr;
 use core::num::ParseIntError;
+use core::sync::atomic::{AtomicU32, Ordering};
 
-use crate::led_cmd::{LedCmd, MessageLedPattern};
+use crate::backoff::{
+    CONNECT_BACKOFF_MAX_MS, CONNECT_BACKOFF_MIN_MS, jittered_delay_ms, next_backoff,
+};
+use crate::command::Command;
+use crate::config::Config;
+use crate::error::WebsocketError;
+use crate::framing::{MessageReader, Received, handshake_request, map_ws_error};
+use crate::led_cmd::LED_COUNT;
+use crate::led_cmd::pattern_types;
+#[cfg(feature = "debug-commands")]
+use crate::led_pattern::TableEntry;
+use crate::network::{request_reconnect, rssi};
+use crate::state::{ConnectionState, connection_state, set_connection_state};
+#[cfg(feature = "debug-commands")]
+use crate::status::TABLE_CHUNK_LEN;
+use crate::status::{DeviceStatus, StatusMessage};
+use crate::watchdog::{self, HEARTBEAT_SECS, Subsystem};
 use embassy_executor::Spawner;
-use embassy_futures::select::{Either, select};
+use embassy_futures::select::{Either4, select4};
 use embassy_net::{HardwareAddress, Stack, tcp::TcpSocket};
 use embassy_sync::{
-    blocking_mutex::raw::NoopRawMutex,
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
     channel::{Channel, Receiver, Sender},
+    signal::Signal,
 };
-use embassy_time::Duration;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
 use embedded_websocket as ws;
-use esp_hal::rng::Rng;
+use esp_hal::{gpio::Level, rng::Rng};
+use heapless::{Deque, String};
 use log::{debug, error, info, warn};
 use serde::Serialize;
 use serde_json_core as sj;
 use static_cell::StaticCell;
 
-const BUF_SIZE: usize = 512;
-const MSG_BUF_SIZE: usize = 128;
+const BUF_SIZE: usize = 1024;
+/* Longest reason of the error replies, longer than any command error description */
+const ERROR_REASON_MAX_LEN: usize = 80;
+/* Largest websocket frame header: 2 bytes, 8 bytes of extended length and a 4 bytes mask */
+const MAX_FRAME_HEADER_LEN: usize = 14;
+/* Outgoing messages are framed in a BUF_SIZE buffer, which must also hold the frame header */
+const MSG_BUF_SIZE: usize = BUF_SIZE - MAX_FRAME_HEADER_LEN;
 const SOCKET_TIMEOUT_SECS: u64 = 8;
 const SOCKET_KEEPALIVE_SECS: u64 = 5;
+/* The TCP connection may outlive the server, which is detected by pinging it regularly: a missing
+ * pong is handled as a disconnection
+ */
+const PING_INTERVAL_SECS: u64 = 10;
+const PONG_TIMEOUT_SECS: u64 = 5;
+/* Right after (re)connection, the first messages are paced to avoid bursting queued messages
+ * at a server which is possibly recovering
+ */
+const SLOW_START_MSG_COUNT: u8 = 3;
+const SLOW_START_INTERVAL_MS: u64 = 50;
+/* Retrying right away after running out of buffers would only make things worse */
+const OUT_OF_BUFFERS_RETRY_DELAY_MS: u64 = 5000;
+const REFUSED_CONNECTION_RETRY_DELAY_MS: u64 = 5000;
+/* A DHCP server not answering in time is handled by joining the access point again */
+const ADDRESS_TIMEOUT_SECS: u64 = 20;
+/* On intentional shutdown, the server is given this long to echo our close frame */
+const CLOSE_TIMEOUT_MS: u64 = 1000;
+
+/* Outgoing messages waiting to be sent. Buzzes happening while disconnected are queued here when
+ * the offline buzz policy allows it, and flushed with their original press time once reconnected
+ * (and identified), while other messages are dropped on disconnection. A buzz not fitting in the
+ * queue is dropped right away, which the player is told with the led.
+ */
+const TX_QUEUE_DEPTH: usize = 3;
+/* Buzzes still queued when the connection drops, kept aside for the next session */
+const CARRIED_OVER_DEPTH: usize = TX_QUEUE_DEPTH;
+
+/* Number of times the websocket ran out of buffers, reported in status messages */
+static RESOURCE_ERRORS: AtomicU32 = AtomicU32::new(0);
+/* Shutdown request sent to the websocket task, and its completion */
+static SHUTDOWN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static CLOSED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 const WEBSOCKET_SERVER_PORT: Result<u16, ParseIntError> =
     u16::from_str_radix(env!("NBC_BACKEND_PORT"), 10);
+/* Server address, the network gateway (i.e. the NBC access point) being used when unset */
+const WEBSOCKET_SERVER_HOST: Option<&str> = option_env!("NBC_BACKEND_HOST");
+const WEBSOCKET_SERVER_PATH: &str = env!("NBC_BACKEND_PATH");
+/* Longest "a.b.c.d:port" host header */
+const HOST_HEADER_MAX_LEN: usize = 21;
 
-pub enum StatusMessage {
-    Identification,
-    Buzz,
+pub struct Websocket {
+    tx_channel: Sender<'static, NoopRawMutex, StatusMessage, TX_QUEUE_DEPTH>,
+    /* Sequence number of the next buzz */
+    next_seq: u32,
 }
 
-impl From<StatusMessage> for &str {
-    fn from(value: StatusMessage) -> Self {
-        match value {
-            StatusMessage::Identification => "identification",
-            StatusMessage::Buzz => "buzz",
-        }
+#[derive(Serialize)]
+struct BuzzData<'a, 'b> {
+    r#type: &'a str,
+    id: &'b str,
+    /// Sequence number, acknowledged by the host
+    seq: u32,
+    tiebreak: u32,
+    simulated: bool,
+    /// Time of the button press, in milliseconds since the buzzer booted
+    timestamp_boot_ms: u64,
+}
+
+#[derive(Serialize)]
+struct ReleaseData<'a, 'b> {
+    r#type: &'a str,
+    id: &'b str,
+    held_ms: u32,
+}
+
+/// Message without any payload
+#[derive(Serialize)]
+struct EventData<'a, 'b> {
+    r#type: &'a str,
+    id: &'b str,
+}
+
+#[derive(Serialize)]
+struct IdentificationData<'a, 'b, 'c> {
+    r#type: &'a str,
+    id: &'b str,
+    name: &'c str,
+    fw_version: &'static str,
+    capabilities: Capabilities,
+}
+
+/// Device capabilities advertised to the host so it can adapt its UI
+#[derive(Serialize)]
+struct Capabilities {
+    patterns: PatternTypes,
+    led_count: usize,
+}
+
+/// Serializes the pattern types known by [`crate::led_cmd`] as a JSON array
+struct PatternTypes;
+
+impl Serialize for PatternTypes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(pattern_types())
     }
 }
 
-pub struct Websocket {
-    tx_channel: Sender<'static, NoopRawMutex, StatusMessage, 3>,
+#[derive(Serialize)]
+struct ConfigData<'a, 'b, 'c> {
+    r#type: &'a str,
+    id: &'b str,
+    config: &'c Config,
+}
+
+#[derive(Serialize)]
+struct StateData<'a, 'b, 'c> {
+    r#type: &'a str,
+    id: &'b str,
+    state: &'c Config,
+}
+
+#[cfg(feature = "debug-commands")]
+#[derive(Serialize)]
+struct TableData<'a, 'b, 'c> {
+    r#type: &'a str,
+    id: &'b str,
+    offset: usize,
+    len: usize,
+    entries: &'c [TableEntry],
 }
 
 #[derive(Serialize)]
-struct StatusMessageData<'a, 'b> {
+struct ButtonLevelData<'a, 'b> {
     r#type: &'a str,
     id: &'b str,
+    level: &'static str,
+}
+
+#[derive(Serialize)]
+struct TemperatureData<'a, 'b> {
+    r#type: &'a str,
+    id: &'b str,
+    celsius: f32,
+}
+
+#[derive(Serialize)]
+struct RssiData<'a, 'b> {
+    r#type: &'a str,
+    id: &'b str,
+    value: i8,
+}
+
+#[derive(Serialize)]
+struct ErrorData<'a, 'b> {
+    r#type: &'a str,
+    id: &'b str,
+    reason: &'a str,
+}
+
+#[derive(Serialize)]
+struct BatteryData<'a, 'b> {
+    r#type: &'a str,
+    id: &'b str,
+    percent: u8,
+}
+
+#[derive(Serialize)]
+struct DeviceStatusData<'a, 'b> {
+    r#type: &'a str,
+    id: &'b str,
+    uptime_ms: u64,
+    boot_count: u32,
+    crash_count: u32,
+    rejected_buzzes: u32,
+    resource_errors: u32,
+    connection: &'static str,
+    temperature_celsius: f32,
+    free_heap: usize,
+    rssi: Option<i8>,
+    led_pattern: &'static str,
+    locked: bool,
+    fw_version: &'static str,
 }
 
 pub enum WebsocketEvent {
     Connected,
     Disconnected,
-    Command(LedCmd),
+    /// The wifi link is up, but no IP address was obtained in time: the access point is being
+    /// joined again
+    AddressTimeout,
+    Command(Command),
 }
 
-static CHANNEL: StaticCell<Channel<NoopRawMutex, StatusMessage, 3>> = StaticCell::new();
+static CHANNEL: StaticCell<Channel<NoopRawMutex, StatusMessage, TX_QUEUE_DEPTH>> =
+    StaticCell::new();
 
 impl Websocket {
     pub fn new(
@@ -64,6 +241,7 @@ impl Websocket {
         let tx_channel: &'static mut _ = CHANNEL.init(Channel::new());
         let res = Websocket {
             tx_channel: tx_channel.sender(),
+            next_seq: 0,
         };
         spawner.spawn(
             websocket_task(
@@ -77,13 +255,158 @@ impl Websocket {
         res
     }
 
-    pub async fn send_identify(&mut self) {
+    /// Close the websocket connection with a normal closure handshake, so that the server does not
+    /// see an abnormal disconnection, e.g. before rebooting. The connection is not established
+    /// again afterwards.
+    pub async fn shutdown(&mut self) {
+        if connection_state() != ConnectionState::WsConnected {
+            return;
+        }
+        info!("Closing websocket connection");
+        SHUTDOWN.signal(());
+        /* Sending the close frame may also take some time */
+        if with_timeout(Duration::from_millis(2 * CLOSE_TIMEOUT_MS), CLOSED.wait())
+            .await
+            .is_err()
+        {
+            warn!("Websocket connection not closed in time");
+        }
+    }
+
+    /* Replies to the host never block either: the websocket task may itself be waiting for main
+     * to take a command, and would never get to empty the queue
+     */
+    pub fn send_identify(&mut self, name: &str) -> Result<(), WebsocketError> {
         info!("Sending identify message");
-        self.tx_channel.send(StatusMessage::Identification).await;
+        let name = String::try_from(name).unwrap_or_default();
+        self.tx_channel
+            .try_send(StatusMessage::Identification(name))
+            .map_err(|_| WebsocketError::QueueFull)
+    }
+    /// Queue a buzz message, without ever blocking: the buzz is dropped if the queue is full.
+    /// Returns the sequence number the host acknowledges the buzz with.
+    pub fn send_button_pushed(
+        &mut self,
+        tiebreak: Option<u32>,
+        simulated: bool,
+        pressed_at: Instant,
+    ) -> Result<u32, WebsocketError> {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.resend_button_pushed(seq, tiebreak, simulated, pressed_at)?;
+        Ok(seq)
+    }
+    /// Queue a buzz message again, keeping its sequence number so that the host can tell
+    /// retransmissions apart from new buzzes
+    pub fn resend_button_pushed(
+        &mut self,
+        seq: u32,
+        tiebreak: Option<u32>,
+        simulated: bool,
+        pressed_at: Instant,
+    ) -> Result<(), WebsocketError> {
+        info!("Sending buzz message {seq}");
+        self.tx_channel
+            .try_send(StatusMessage::Buzz {
+                seq,
+                tiebreak,
+                simulated,
+                pressed_at,
+            })
+            .map_err(|_| WebsocketError::QueueFull)
+    }
+    /// Queue a button release message, without ever blocking: the message is dropped if the queue
+    /// is full
+    pub fn send_button_released(&mut self, held: Duration) -> Result<(), WebsocketError> {
+        info!("Sending release message");
+        self.tx_channel
+            .try_send(StatusMessage::Release {
+                held_ms: held.as_millis().try_into().unwrap_or(u32::MAX),
+            })
+            .map_err(|_| WebsocketError::QueueFull)
+    }
+    pub fn send_status(&mut self, status: DeviceStatus) -> Result<(), WebsocketError> {
+        info!("Sending status message");
+        self.tx_channel
+            .try_send(StatusMessage::Status(status))
+            .map_err(|_| WebsocketError::QueueFull)
     }
-    pub async fn send_button_pushed(&mut self) {
-        info!("Sending buzz message");
-        self.tx_channel.send(StatusMessage::Buzz).await;
+    pub fn send_config(&mut self, config: Config) -> Result<(), WebsocketError> {
+        info!("Sending config message");
+        self.tx_channel
+            .try_send(StatusMessage::Config(config))
+            .map_err(|_| WebsocketError::QueueFull)
+    }
+    /// Send the configuration to be cloned to other buzzers
+    pub fn send_state(&mut self, state: Config) -> Result<(), WebsocketError> {
+        info!("Sending state message");
+        self.tx_channel
+            .try_send(StatusMessage::State(state))
+            .map_err(|_| WebsocketError::QueueFull)
+    }
+    /// Send a brightness table, split in several messages to fit in the websocket buffers. The
+    /// chunks not fitting in the queue are dropped, the host telling from their offset and length
+    /// that the dump is incomplete.
+    #[cfg(feature = "debug-commands")]
+    pub fn send_table(&mut self, table: &[TableEntry]) -> Result<(), WebsocketError> {
+        info!("Sending brightness table ({} entries)", table.len());
+        let mut offset = 0;
+        loop {
+            let end = table.len().min(offset + TABLE_CHUNK_LEN);
+            let entries = heapless::Vec::from_slice(&table[offset..end]).unwrap_or_default();
+            self.tx_channel
+                .try_send(StatusMessage::Table {
+                    offset,
+                    len: table.len(),
+                    entries,
+                })
+                .map_err(|_| WebsocketError::QueueFull)?;
+            offset = end;
+            if offset >= table.len() {
+                return Ok(());
+            }
+        }
+    }
+    pub fn send_temperature(&mut self, celsius: f32) -> Result<(), WebsocketError> {
+        info!("Sending temperature message");
+        self.tx_channel
+            .try_send(StatusMessage::Temperature(celsius))
+            .map_err(|_| WebsocketError::QueueFull)
+    }
+
+    /// Report the wifi signal strength, in dBm. Reports are periodic, so one is dropped rather
+    /// than waited for when the queue is full.
+    pub fn send_rssi(&mut self, rssi: i8) -> Result<(), WebsocketError> {
+        info!("Sending RSSI message");
+        self.tx_channel
+            .try_send(StatusMessage::Rssi(rssi))
+            .map_err(|_| WebsocketError::QueueFull)
+    }
+
+    /// Report the battery charge, in percent. Like RSSI reports, battery reports are dropped when
+    /// the queue is full.
+    pub fn send_battery(&mut self, percent: u8) -> Result<(), WebsocketError> {
+        info!("Sending battery message");
+        self.tx_channel
+            .try_send(StatusMessage::Battery(percent))
+            .map_err(|_| WebsocketError::QueueFull)
+    }
+
+    /// Tell the host that the led went off, for it to update its view of the buzzer. Never
+    /// blocks: the message is dropped if the queue is full.
+    pub fn send_led_idle(&mut self) -> Result<(), WebsocketError> {
+        info!("Sending led idle message");
+        self.tx_channel
+            .try_send(StatusMessage::LedIdle)
+            .map_err(|_| WebsocketError::QueueFull)
+    }
+    pub fn send_button_level(&mut self, level: Level) -> Result<(), WebsocketError> {
+        info!("Sending button level message");
+        self.tx_channel
+            .try_send(StatusMessage::ButtonLevel {
+                high: level == Level::High,
+            })
+            .map_err(|_| WebsocketError::QueueFull)
     }
 }
 
@@ -98,40 +421,220 @@ fn format_status_message(
         mac_buf[i * 2] = HEX_CHARS[(byte >> 4) as usize];
         mac_buf[i * 2 + 1] = HEX_CHARS[(byte & 0xf) as usize];
     }
-    let ident = StatusMessageData {
-        r#type: status.into(),
-        id: core::str::from_utf8(&mac_buf).unwrap_or("invalid_mac"),
-    };
-    sj::to_slice(&ident, buf)
+    let r#type = (&status).into();
+    let id = core::str::from_utf8(&mac_buf).unwrap_or("invalid_mac");
+    match status {
+        StatusMessage::Identification(name) => sj::to_slice(
+            &IdentificationData {
+                r#type,
+                id,
+                name: &name,
+                fw_version: env!("CARGO_PKG_VERSION"),
+                capabilities: Capabilities {
+                    patterns: PatternTypes,
+                    led_count: LED_COUNT,
+                },
+            },
+            buf,
+        ),
+        StatusMessage::Buzz {
+            seq,
+            tiebreak,
+            simulated,
+            pressed_at,
+        } => sj::to_slice(
+            &BuzzData {
+                r#type,
+                id,
+                seq,
+                /* Default token is stable across reboots, and unique among devices sharing the
+                 * same MAC vendor prefix
+                 */
+                tiebreak: tiebreak.unwrap_or_else(|| {
+                    mac.iter()
+                        .skip(2)
+                        .take(4)
+                        .fold(0, |acc, &byte| (acc << 8) | u32::from(byte))
+                }),
+                simulated,
+                timestamp_boot_ms: pressed_at.as_millis(),
+            },
+            buf,
+        ),
+        StatusMessage::Release { held_ms } => sj::to_slice(
+            &ReleaseData {
+                r#type,
+                id,
+                held_ms,
+            },
+            buf,
+        ),
+        #[cfg(feature = "debug-commands")]
+        StatusMessage::Table {
+            offset,
+            len,
+            entries,
+        } => sj::to_slice(
+            &TableData {
+                r#type,
+                id,
+                offset,
+                len,
+                entries: &entries,
+            },
+            buf,
+        ),
+        StatusMessage::ButtonLevel { high } => sj::to_slice(
+            &ButtonLevelData {
+                r#type,
+                id,
+                level: if high { "high" } else { "low" },
+            },
+            buf,
+        ),
+        StatusMessage::LedIdle => sj::to_slice(&EventData { r#type, id }, buf),
+        StatusMessage::Temperature(celsius) => sj::to_slice(
+            &TemperatureData {
+                r#type,
+                id,
+                celsius,
+            },
+            buf,
+        ),
+        StatusMessage::Rssi(value) => sj::to_slice(&RssiData { r#type, id, value }, buf),
+        StatusMessage::Error(e) => {
+            let mut reason: String<ERROR_REASON_MAX_LEN> = String::new();
+            /* Can not overflow, command error descriptions fit in the reason */
+            let _ = write!(reason, "{e}");
+            sj::to_slice(
+                &ErrorData {
+                    r#type,
+                    id,
+                    reason: &reason,
+                },
+                buf,
+            )
+        }
+        StatusMessage::Battery(percent) => sj::to_slice(
+            &BatteryData {
+                r#type,
+                id,
+                percent,
+            },
+            buf,
+        ),
+        StatusMessage::Config(config) => sj::to_slice(
+            &ConfigData {
+                r#type,
+                id,
+                config: &config,
+            },
+            buf,
+        ),
+        StatusMessage::State(state) => sj::to_slice(
+            &StateData {
+                r#type,
+                id,
+                state: &state,
+            },
+            buf,
+        ),
+        StatusMessage::Status(status) => sj::to_slice(
+            &DeviceStatusData {
+                r#type,
+                id,
+                uptime_ms: Instant::now().as_millis(),
+                boot_count: status.boot_count,
+                crash_count: status.crash_count,
+                rejected_buzzes: status.rejected_buzzes,
+                resource_errors: RESOURCE_ERRORS.load(Ordering::Relaxed),
+                connection: connection_state().name(),
+                temperature_celsius: status.temperature,
+                free_heap: esp_alloc::HEAP.free(),
+                rssi: rssi(),
+                led_pattern: status.led_pattern,
+                locked: status.locked,
+                fw_version: env!("CARGO_PKG_VERSION"),
+            },
+            buf,
+        ),
+    }
 }
 
 static RX_BUFFER: StaticCell<[u8; BUF_SIZE]> = StaticCell::new();
 static TX_BUFFER: StaticCell<[u8; BUF_SIZE]> = StaticCell::new();
 static CONNECT_BUFFER: StaticCell<[u8; BUF_SIZE]> = StaticCell::new();
 static FRAME_BUFFER: StaticCell<[u8; BUF_SIZE]> = StaticCell::new();
+static READ_BUFFER: StaticCell<[u8; BUF_SIZE]> = StaticCell::new();
+
+/// Wait for a retry delay, randomly spread around its nominal value. The hardware RNG makes the
+/// spread different on each device.
+async fn retry_delay(delay_ms: u64) {
+    let delay_ms = jittered_delay_ms(delay_ms, Rng::new().random());
+    Timer::after(Duration::from_millis(delay_ms)).await;
+}
+
+async fn write_frame(socket: &mut TcpSocket<'_>, frame: &[u8]) -> Result<(), WebsocketError> {
+    let written = socket.write(frame).await.map_err(WebsocketError::Write)?;
+    if written < frame.len() {
+        return Err(WebsocketError::OutOfBuffers);
+    }
+    Ok(())
+}
+
+fn report_error(context: &str, e: WebsocketError) {
+    if e == WebsocketError::OutOfBuffers {
+        RESOURCE_ERRORS.store(
+            RESOURCE_ERRORS.load(Ordering::Relaxed).wrapping_add(1),
+            Ordering::Relaxed,
+        );
+        error!("{context}: {e}, network buffers are too small for the enabled features!");
+    } else {
+        error!("{context}: {e}");
+    }
+}
+
+/// Check whether the server handshake response enables permessage-deflate, which we never offer
+/// and can not decode
+fn response_enables_deflate(response: &[u8]) -> bool {
+    const EXTENSION: &[u8] = b"permessage-deflate";
+    let headers_len = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(response.len());
+    response[..headers_len]
+        .windows(EXTENSION.len())
+        .any(|w| w.eq_ignore_ascii_case(EXTENSION))
+}
 
 async fn websocket_handshake<'a>(
     client: &mut ws::WebSocketClient<Rng>,
     socket: &mut TcpSocket<'a>,
     buffer: &mut [u8],
-) -> Result<ws::WebSocketKey, &'static str> {
-    let websocket_options = ws::WebSocketOptions {
-        path: "/ws",
-        host: "",
-        origin: "http://localhost:1337",
-        sub_protocols: None,
-        additional_headers: None,
-    };
-    let (count, key) = client
-        .client_connect(&websocket_options, buffer)
-        .map_err(|_| "failed to generate connect message")?;
-    socket
-        .write(&buffer[..count])
-        .await
-        .map_err(|_| "failed to write handshake")?;
+    host: &str,
+) -> Result<ws::WebSocketKey, WebsocketError> {
+    let (count, key) = handshake_request(client, WEBSOCKET_SERVER_PATH, host, buffer)?;
+    write_frame(socket, &buffer[..count]).await?;
     Ok(key)
 }
 
+/// Send a ping or pong frame
+async fn send_control_frame<'a>(
+    client: &mut ws::WebSocketClient<Rng>,
+    socket: &mut TcpSocket<'a>,
+    buffer: &mut [u8],
+    message_type: ws::WebSocketSendMessageType,
+    payload: &[u8],
+) {
+    let res = match client.write(message_type, true, payload, buffer) {
+        Ok(count) => write_frame(socket, &buffer[..count]).await,
+        Err(e) => Err(map_ws_error(e)),
+    };
+    if let Err(e) = res {
+        report_error("Failed to send control frame", e);
+    }
+}
+
 async fn send_status_message<'a>(
     client: &mut ws::WebSocketClient<Rng>,
     socket: &mut TcpSocket<'a>,
@@ -140,9 +643,11 @@ async fn send_status_message<'a>(
     mac: &[u8],
 ) {
     let mut msg_buf = [0u8; MSG_BUF_SIZE];
-    let prepare_result: Result<usize, &'static str> = (|| {
-        let len =
-            format_status_message(&mut msg_buf, status, mac).map_err(|_| "failed to serialize")?;
+    let prepare_result: Result<usize, WebsocketError> = (|| {
+        let len = format_status_message(&mut msg_buf, status, mac).map_err(|e| match e {
+            sj::ser::Error::BufferFull => WebsocketError::OutOfBuffers,
+            _ => WebsocketError::Serialization,
+        })?;
         debug!(
             "Sending new websocket message: {} ({} bytes)",
             core::str::from_utf8(&msg_buf[..len]).unwrap_or("<invalid utf8>"),
@@ -155,19 +660,72 @@ async fn send_status_message<'a>(
                 &msg_buf[..len],
                 buffer,
             )
-            .map_err(|_| "failed to encode websocket frame")?;
+            .map_err(map_ws_error)?;
         Ok(count)
     })();
 
     match prepare_result {
-        Ok(count) => {
-            if let Err(e) = socket.write(&buffer[..count]).await {
-                error!("Failed to send message: {:?}", e);
-            } else {
-                debug!("Message sent");
+        Ok(count) => match write_frame(socket, &buffer[..count]).await {
+            Ok(()) => debug!("Message sent"),
+            Err(e) => report_error("Failed to send message", e),
+        },
+        Err(e) => report_error("Failed to prepare message", e),
+    }
+}
+
+/// Send a close frame, and wait for the server to echo it before aborting the socket. Data
+/// received in the meantime is discarded.
+async fn close_session(
+    client: &mut ws::WebSocketClient<Rng>,
+    socket: &mut TcpSocket<'_>,
+    buffer: &mut [u8],
+    reader: &mut MessageReader<'_>,
+) {
+    let res = match client.close(ws::WebSocketCloseStatusCode::NormalClosure, None, buffer) {
+        Ok(count) => write_frame(socket, &buffer[..count]).await,
+        Err(e) => Err(map_ws_error(e)),
+    };
+    if let Err(e) = res {
+        report_error("Failed to send close frame", e);
+        socket.abort();
+        return;
+    }
+    let wait_close = async {
+        loop {
+            let count = match socket.read(reader.spare()).await {
+                Ok(0) | Err(_) => return,
+                Ok(count) => count,
+            };
+            reader.received(count);
+            while let Some(received) = reader.next(client) {
+                if let Received::Message(ws::WebSocketReceiveMessageType::CloseCompleted, _) =
+                    received
+                {
+                    return;
+                }
             }
         }
-        Err(e) => error!("Failed to prepare message: {e}"),
+    };
+    match with_timeout(Duration::from_millis(CLOSE_TIMEOUT_MS), wait_close).await {
+        Ok(()) => info!("Websocket connection closed"),
+        Err(_) => warn!("No close frame received from NBC websocket server"),
+    }
+    socket.abort();
+}
+
+/// Discard the queued messages made stale by a disconnection, and keep the others aside to be sent
+/// in the next session
+fn drain_tx_queue(
+    tx_channel: &Receiver<'static, NoopRawMutex, StatusMessage, TX_QUEUE_DEPTH>,
+    carried_over: &mut Deque<StatusMessage, TX_QUEUE_DEPTH>,
+) {
+    while let Ok(msg) = tx_channel.try_receive() {
+        let name: &str = (&msg).into();
+        if !msg.outlives_session() {
+            info!("Dropping stale {name} message");
+        } else if carried_over.push_back(msg).is_err() {
+            warn!("Dropping {name} message, too many messages kept for the next session");
+        }
     }
 }
 
@@ -175,59 +733,158 @@ async fn send_status_message<'a>(
 pub async fn websocket_task(
     stack: Stack<'static>,
     rx_channel: Sender<'static, NoopRawMutex, WebsocketEvent, 3>,
-    tx_channel: Receiver<'static, NoopRawMutex, StatusMessage, 3>,
+    tx_channel: Receiver<'static, NoopRawMutex, StatusMessage, TX_QUEUE_DEPTH>,
     mac: HardwareAddress,
 ) {
     let rx_buffer = RX_BUFFER.init([0u8; BUF_SIZE]);
     let tx_buffer = TX_BUFFER.init([0u8; BUF_SIZE]);
     let connect_buffer = CONNECT_BUFFER.init([0u8; BUF_SIZE]);
-    let frame_buffer = FRAME_BUFFER.init([0u8; BUF_SIZE]);
+    let mut reader = MessageReader::new(
+        READ_BUFFER.init([0u8; BUF_SIZE]),
+        FRAME_BUFFER.init([0u8; BUF_SIZE]),
+    );
 
     let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    /* The hardware RNG is a true entropy source as long as the radio is running, which is the
+     * case once the wifi controller is initialized. The client (and thus its RNG) lives for the
+     * whole task, so handshake keys and frame masks never replay the same sequence across
+     * reconnections.
+     */
     let mut client = ws::WebSocketClient::new_client(Rng::new());
-    let mut connected: bool = false;
+    let mut last_key: Option<ws::WebSocketKey> = None;
+    let mut slow_start_remaining: u8 = 0;
+    /* Messages kept from a previous session, only sent once the host knows who we are */
+    let mut carried_over: Deque<StatusMessage, TX_QUEUE_DEPTH> = Deque::new();
+    let mut identified = false;
+    let mut last_sent = Instant::now();
+    let mut backoff_ms = CONNECT_BACKOFF_MIN_MS;
+    let mut next_ping = Instant::now();
+    let mut pong_deadline: Option<Instant> = None;
+    #[cfg(feature = "latency-stats")]
+    let mut command_latency = crate::latency::LatencyStats::new("Command");
     socket.set_timeout(Some(Duration::from_secs(SOCKET_TIMEOUT_SECS)));
     socket.set_keep_alive(Some(Duration::from_secs(SOCKET_KEEPALIVE_SECS)));
 
     info!("Starting websocket task");
     loop {
+        watchdog::feed(Subsystem::Websocket);
         if !stack.is_config_up() {
+            /* The link may stay down for long, e.g. while the access point is off */
+            while with_timeout(Duration::from_secs(HEARTBEAT_SECS), stack.wait_link_up())
+                .await
+                .is_err()
+            {
+                watchdog::feed(Subsystem::Websocket);
+            }
             info!("Waiting for network configuration...");
-            stack.wait_config_up().await;
+            let timeout = Duration::from_secs(ADDRESS_TIMEOUT_SECS);
+            if with_timeout(timeout, stack.wait_config_up()).await.is_err() {
+                error!(
+                    "No DHCP lease after {ADDRESS_TIMEOUT_SECS} s, joining the access point again"
+                );
+                request_reconnect();
+                rx_channel.send(WebsocketEvent::AddressTimeout).await;
+                continue;
+            }
         }
         info!("Network configuration done");
         let Some(config) = stack.config_v4() else {
             error!("Missing network configuration after wait_config_up");
             continue;
         };
-        let Some(server_address) = config.gateway else {
-            error!("Missing gateway address in network configuration");
-            continue;
+        let server_address = match WEBSOCKET_SERVER_HOST {
+            Some(host) => match host.parse::<Ipv4Addr>() {
+                Ok(address) => address,
+                Err(_) => {
+                    error!("Invalid server address configuration: {host}");
+                    retry_delay(CONNECT_BACKOFF_MAX_MS).await;
+                    continue;
+                }
+            },
+            None => match config.gateway {
+                Some(gateway) => gateway,
+                None => {
+                    error!("Missing gateway address in network configuration");
+                    retry_delay(CONNECT_BACKOFF_MAX_MS).await;
+                    continue;
+                }
+            },
         };
         let Ok(port) = WEBSOCKET_SERVER_PORT else {
             error!("Invalid server port configuration");
             continue;
         };
         let remote = (server_address, port);
+        let mut host: String<HOST_HEADER_MAX_LEN> = String::new();
+        /* Can not overflow, the longest address and port fit in the header */
+        let _ = write!(host, "{server_address}:{port}");
         info!("Connecting to NBC TCP server...");
+        set_connection_state(&[ConnectionState::WifiUp], ConnectionState::Connecting);
         let res = socket.connect(remote).await;
         if let Err(e) = res {
             error!("Failed to connect to TCP server: {:?}", e);
+            set_connection_state(&[ConnectionState::Connecting], ConnectionState::WifiUp);
+            retry_delay(backoff_ms).await;
+            backoff_ms = next_backoff(backoff_ms);
             continue;
         }
         info!("Connected to NBC TCP server");
         while socket.state() == embassy_net::tcp::State::Established {
+            watchdog::feed(Subsystem::Websocket);
             info!("Connecting to NBC websocket server...");
-            let ws_key = match websocket_handshake(&mut client, &mut socket, connect_buffer).await {
-                Ok(key) => key,
-                Err(e) => {
-                    error!("WebSocket handshake failed: {e}");
-                    continue;
-                }
-            };
+            let ws_key =
+                match websocket_handshake(&mut client, &mut socket, connect_buffer, &host).await {
+                    Ok(key) => key,
+                    Err(e) => {
+                        report_error("WebSocket handshake failed", e);
+                        if e == WebsocketError::OutOfBuffers {
+                            retry_delay(OUT_OF_BUFFERS_RETRY_DELAY_MS.max(backoff_ms)).await;
+                        } else {
+                            retry_delay(backoff_ms).await;
+                        }
+                        backoff_ms = next_backoff(backoff_ms);
+                        continue;
+                    }
+                };
+            if last_key.as_ref() == Some(&ws_key) {
+                error!("WebSocket key reused across connections, RNG entropy looks broken");
+            }
+            last_key = Some(ws_key.clone());
+            reader.reset();
             loop {
-                match select(socket.read(connect_buffer), tx_channel.receive()).await {
-                    Either::First(x) => match x {
+                watchdog::feed(Subsystem::Websocket);
+                /* Queued messages are only sent once the websocket connection is accepted */
+                let tx = async {
+                    if connection_state() != ConnectionState::WsConnected {
+                        core::future::pending().await
+                    } else {
+                        let carried = if identified {
+                            carried_over.pop_front()
+                        } else {
+                            None
+                        };
+                        match carried {
+                            Some(msg) => msg,
+                            None => tx_channel.receive().await,
+                        }
+                    }
+                };
+                let keepalive = async {
+                    if connection_state() != ConnectionState::WsConnected {
+                        core::future::pending().await
+                    } else {
+                        Timer::at(pong_deadline.unwrap_or(next_ping)).await
+                    }
+                };
+                let shutdown = async {
+                    if connection_state() != ConnectionState::WsConnected {
+                        core::future::pending().await
+                    } else {
+                        SHUTDOWN.wait().await
+                    }
+                };
+                match select4(socket.read(reader.spare()), tx, keepalive, shutdown).await {
+                    Either4::First(x) => match x {
                         Ok(0) => {
                             info!("Socket is closed");
                             let res = client.close(
@@ -239,14 +896,21 @@ pub async fn websocket_task(
                                 error!("Failed to close client after TCP disconnect: {:?}", e);
                             }
                             socket.abort();
-                            connected = false;
+                            set_connection_state(
+                                &[ConnectionState::Connecting, ConnectionState::WsConnected],
+                                ConnectionState::WifiUp,
+                            );
+                            drain_tx_queue(&tx_channel, &mut carried_over);
                             rx_channel.send(WebsocketEvent::Disconnected).await;
                             break;
                         }
                         Err(e) => {
                             error!("Can not read socket: {:?}", e);
                             socket.close();
-                            connected = false;
+                            set_connection_state(
+                                &[ConnectionState::Connecting, ConnectionState::WsConnected],
+                                ConnectionState::WifiUp,
+                            );
                             if let Err(e) = client.close(
                                 ws::WebSocketCloseStatusCode::EndpointUnavailable,
                                 None,
@@ -254,53 +918,131 @@ pub async fn websocket_task(
                             ) {
                                 warn!("Failed to close websocket client: {:?}", e);
                             }
+                            drain_tx_queue(&tx_channel, &mut carried_over);
                             rx_channel.send(WebsocketEvent::Disconnected).await;
                             break;
                         }
                         Ok(count) => {
+                            #[cfg(feature = "latency-stats")]
+                            let received = Instant::now();
+                            let data = reader.received(count);
                             debug!(
                                 "New TCP data: {:?} ({} bytes)",
-                                str::from_utf8(&connect_buffer[..count])
-                                    .unwrap_or("invalid_string"),
+                                str::from_utf8(data).unwrap_or("invalid_string"),
                                 count
                             );
-                            if !connected {
-                                match client.client_accept(&ws_key, &connect_buffer[..count]) {
+                            if connection_state() != ConnectionState::WsConnected {
+                                if response_enables_deflate(data) {
+                                    report_error(
+                                        "Refusing websocket connection",
+                                        WebsocketError::UnsupportedExtension,
+                                    );
+                                    socket.abort();
+                                    set_connection_state(
+                                        &[ConnectionState::Connecting],
+                                        ConnectionState::WifiUp,
+                                    );
+                                    retry_delay(REFUSED_CONNECTION_RETRY_DELAY_MS).await;
+                                    break;
+                                }
+                                let accepted = client.client_accept(&ws_key, data);
+                                /* Frames are only decoded once the handshake is over */
+                                reader.reset();
+                                match accepted {
                                     Ok(_) => {
-                                        connected = true;
+                                        set_connection_state(
+                                            &[ConnectionState::Connecting],
+                                            ConnectionState::WsConnected,
+                                        );
+                                        slow_start_remaining = SLOW_START_MSG_COUNT;
+                                        backoff_ms = CONNECT_BACKOFF_MIN_MS;
+                                        next_ping = Instant::now()
+                                            + Duration::from_secs(PING_INTERVAL_SECS);
+                                        pong_deadline = None;
+                                        identified = false;
                                         info!("Connected to NBC websocket server");
                                         rx_channel.send(WebsocketEvent::Connected).await;
                                     }
                                     Err(e) => error!("Can not accept connection: {:?}", e),
                                 }
-                            } else if let Ok(ws_frame) = client.read(connect_buffer, frame_buffer) {
-                                debug!("WS message parsing status: {:?}", ws_frame);
+                                continue;
+                            }
+                            while let Some(decoded) = reader.next(&mut client) {
+                                let (message_type, msg) = match decoded {
+                                    Received::Ping(payload) => {
+                                        debug!("Received ping, sending pong");
+                                        send_control_frame(
+                                            &mut client,
+                                            &mut socket,
+                                            connect_buffer,
+                                            ws::WebSocketSendMessageType::Pong,
+                                            payload,
+                                        )
+                                        .await;
+                                        continue;
+                                    }
+                                    Received::Pong => {
+                                        debug!("Received pong");
+                                        pong_deadline = None;
+                                        next_ping = Instant::now()
+                                            + Duration::from_secs(PING_INTERVAL_SECS);
+                                        continue;
+                                    }
+                                    Received::Oversized => {
+                                        error!(
+                                            "Dropping websocket message larger than {BUF_SIZE} bytes"
+                                        );
+                                        continue;
+                                    }
+                                    Received::Invalid(e) => {
+                                        error!("Failed to decode received message: {:?}", e);
+                                        continue;
+                                    }
+                                    Received::Message(message_type, msg) => (message_type, msg),
+                                };
                                 debug!(
                                     "Received websocket message {:?} ({} bytes)",
-                                    str::from_utf8(&frame_buffer[..ws_frame.len_to])
-                                        .unwrap_or("invalid text"),
-                                    ws_frame.len_to
+                                    str::from_utf8(msg).unwrap_or("invalid text"),
+                                    msg.len()
                                 );
-                                let cmd = serde_json_core::from_slice::<MessageLedPattern>(
-                                    &frame_buffer[..ws_frame.len_to],
-                                )
-                                .map_err(|e| warn!("Failed to decode JSON message: {e}"))
-                                .and_then(|(data, _)| {
-                                    data.try_into()
-                                        .map_err(|e| warn!("Failed to decode command: {e}"))
-                                });
-                                if let Ok(cmd) = cmd {
-                                    rx_channel.send(WebsocketEvent::Command(cmd)).await;
+                                let cmd = match message_type {
+                                    #[cfg(feature = "binary-commands")]
+                                    ws::WebSocketReceiveMessageType::Binary => {
+                                        Command::parse_binary(msg)
+                                    }
+                                    _ => Command::parse(msg),
+                                };
+                                match cmd {
+                                    Ok(cmd) => {
+                                        rx_channel.send(WebsocketEvent::Command(cmd)).await;
+                                        #[cfg(feature = "latency-stats")]
+                                        command_latency.record(received);
+                                    }
+                                    Err(e) => {
+                                        warn!("Failed to decode command: {e}");
+                                        /* Let the host know why nothing happened */
+                                        send_status_message(
+                                            &mut client,
+                                            &mut socket,
+                                            connect_buffer,
+                                            StatusMessage::Error(e),
+                                            mac.as_bytes(),
+                                        )
+                                        .await;
+                                    }
                                 }
-                            } else {
-                                error!(
-                                    "Failed to decode received message {:?}",
-                                    &connect_buffer[..count]
-                                );
                             }
                         }
                     },
-                    Either::Second(status) => {
+                    Either4::Second(status) => {
+                        if let StatusMessage::Identification(_) = status {
+                            identified = true;
+                        }
+                        if slow_start_remaining > 0 {
+                            slow_start_remaining -= 1;
+                            Timer::at(last_sent + Duration::from_millis(SLOW_START_INTERVAL_MS))
+                                .await;
+                        }
                         send_status_message(
                             &mut client,
                             &mut socket,
@@ -309,6 +1051,40 @@ pub async fn websocket_task(
                             mac.as_bytes(),
                         )
                         .await;
+                        last_sent = Instant::now();
+                    }
+                    Either4::Third(_) => {
+                        if pong_deadline.is_some() {
+                            error!("No pong received from NBC websocket server, disconnecting");
+                            socket.abort();
+                            set_connection_state(
+                                &[ConnectionState::Connecting, ConnectionState::WsConnected],
+                                ConnectionState::WifiUp,
+                            );
+                            drain_tx_queue(&tx_channel, &mut carried_over);
+                            rx_channel.send(WebsocketEvent::Disconnected).await;
+                            break;
+                        }
+                        debug!("Sending ping");
+                        send_control_frame(
+                            &mut client,
+                            &mut socket,
+                            connect_buffer,
+                            ws::WebSocketSendMessageType::Ping,
+                            &[],
+                        )
+                        .await;
+                        pong_deadline =
+                            Some(Instant::now() + Duration::from_secs(PONG_TIMEOUT_SECS));
+                    }
+                    Either4::Fourth(_) => {
+                        close_session(&mut client, &mut socket, connect_buffer, &mut reader).await;
+                        set_connection_state(
+                            &[ConnectionState::WsConnected],
+                            ConnectionState::WifiUp,
+                        );
+                        CLOSED.signal(());
+                        return;
                     }
                 }
             }
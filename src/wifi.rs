@@ -0,0 +1,57 @@
+use core::fmt;
+
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ConfigError;
+
+pub const SSID_MAX_LEN: usize = 32;
+pub const PASSWORD_MAX_LEN: usize = 64;
+/* Access points tried in turn, e.g. a primary one and a backup one */
+pub const MAX_WIFI_NETWORKS: usize = 3;
+
+/// Optional association hints: when the access point channel (and BSSID) are known, the station
+/// skips scanning all channels and associates faster. A stale channel (e.g. if the access point
+/// moved to another channel) prevents association until the setting is updated or cleared.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct WifiSettings {
+    pub channel: Option<u8>,
+    pub bssid: Option<[u8; 6]>,
+}
+
+/// Access point credentials, provisioned by the host and persisted in flash
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct WifiCredentials {
+    pub ssid: String<SSID_MAX_LEN>,
+    pub password: String<PASSWORD_MAX_LEN>,
+}
+
+impl WifiCredentials {
+    /// Check that the credentials are usable: connecting to an empty SSID would fail forever with
+    /// unhelpful radio errors
+    pub fn check(&self) -> Result<(), ConfigError> {
+        if self.ssid.trim().is_empty() {
+            return Err(ConfigError::EmptySsid);
+        }
+        Ok(())
+    }
+}
+
+/* The password must not end up in the logs */
+impl fmt::Debug for WifiCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WifiCredentials")
+            .field("ssid", &self.ssid)
+            .finish_non_exhaustive()
+    }
+}
+
+pub type WifiNetworks = heapless::Vec<WifiCredentials, MAX_WIFI_NETWORKS>;
+
+/// Check that a list of access points is usable
+pub fn check_networks(networks: &WifiNetworks) -> Result<(), ConfigError> {
+    if networks.is_empty() {
+        return Err(ConfigError::EmptySsid);
+    }
+    networks.iter().try_for_each(WifiCredentials::check)
+}